@@ -0,0 +1,295 @@
+use crate::http::{fmt_http_date, parse_http_date, Method};
+use crate::request::Request;
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// An entity tag (RFC 9110 section 8.8.3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    /// the opaque tag, without its quotes
+    pub tag: String,
+}
+
+impl EntityTag {
+    /// Strong comparison, both tags have to be strong and the same
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Weak comparison, the tags only have to be the same
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+
+    /// Parses a comma separated list of entity tags
+    /// None if any member is malformed
+    pub fn parse_list(value: &str) -> Option<Vec<EntityTag>> {
+        let mut tags = Vec::new();
+        let mut rest = value.trim_start();
+        while !rest.is_empty() {
+            let (weak, quoted) = match rest.strip_prefix("W/") {
+                Some(quoted) => (true, quoted),
+                None => (false, rest),
+            };
+            let quoted = quoted.strip_prefix('"')?;
+            let end = quoted.find('"')?;
+            let tag = &quoted[..end];
+            // etagc is any visible character but DQUOTE
+            if !tag
+                .bytes()
+                .all(|b| b == 0x21 || (0x23..0x7f).contains(&b) || b >= 0x80)
+            {
+                return None;
+            }
+            tags.push(EntityTag {
+                weak,
+                tag: tag.to_string(),
+            });
+
+            rest = quoted[end + 1..].trim_start();
+            match rest.strip_prefix(',') {
+                Some(next) => rest = next.trim_start_matches([' ', '\t', ',']),
+                None if rest.is_empty() => {}
+                None => return None,
+            }
+        }
+        Some(tags)
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// Validators of a representation, sent as ETag and Last-Modified
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<EntityTag>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    /// Validators of a file, the tag made of its length and modification time
+    pub fn for_file(length: u64, modified: Option<DateTime<Utc>>, weak: bool) -> Self {
+        let etag = modified.map(|modified| EntityTag {
+            weak,
+            tag: format!(
+                "{:x}-{:x}.{:x}",
+                length,
+                modified.timestamp(),
+                modified.timestamp_subsec_nanos()
+            ),
+        });
+        Self {
+            etag,
+            last_modified: modified,
+        }
+    }
+
+    /// Validators of the content sent in a coding, told apart by its name
+    /// weak if the coded content is only equivalent to the original
+    pub fn with_encoding(mut self, coding: &str, weak: bool) -> Self {
+        if let Some(etag) = self.etag.as_mut() {
+            etag.tag = format!("{}-{}", etag.tag, coding);
+            etag.weak |= weak;
+        }
+        self
+    }
+
+    /// Headers announcing the validators
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &self.etag {
+            headers.push(("ETag", etag.to_string()));
+        }
+        if let Some(modified) = &self.last_modified {
+            headers.push(("Last-Modified", fmt_http_date(modified)));
+        }
+        headers
+    }
+
+    /// Checks if the validator of an If-Range header still matches, which
+    /// needs a strong comparison
+    ///
+    /// A date only counts when Last-Modified is a strong validator, at least
+    /// a second before `now`, the Date of the response (RFC 9110 section 8.8.2.2).
+    pub fn if_range_matches(&self, value: &str, now: DateTime<Utc>) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') || value.starts_with("W/") {
+            let tags = EntityTag::parse_list(value).unwrap_or_default();
+            return match (&self.etag, tags.as_slice()) {
+                (Some(etag), [tag]) => etag.strong_eq(tag),
+                _ => false,
+            };
+        }
+        match (parse_http_date(value), self.last_modified) {
+            (Some(date), Some(modified)) => {
+                date.timestamp() == modified.timestamp()
+                    && now.timestamp() - modified.timestamp() >= 1
+            }
+            _ => false,
+        }
+    }
+
+    /// Modification time as sent, HTTP-dates only count whole seconds
+    fn modified_secs(&self) -> Option<i64> {
+        self.last_modified.map(|m| m.timestamp())
+    }
+}
+
+/// Outcome of the preconditions of a request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+    /// the request goes ahead as if it had no preconditions
+    Proceed,
+    /// 304, the client's copy is current
+    NotModified,
+    /// 412, the request was made against a different representation
+    Failed,
+}
+
+/// Evaluates the preconditions of a request against the validators of the
+/// representation it targets, in the order of RFC 9110 section 13.2.2
+///
+/// Date conditions only count when there is no entity tag condition to
+/// check instead. Dates that don't parse are ignored.
+pub fn evaluate(req: &Request, validators: &Validators) -> Precondition {
+    let method = req.method();
+    let is_read = method == Method::Get || method == Method::Head;
+
+    if let Some(value) = req.get_header("If-Match") {
+        let matched = value.trim() == "*"
+            || match (&validators.etag, EntityTag::parse_list(value)) {
+                (Some(etag), Some(tags)) => tags.iter().any(|t| t.strong_eq(etag)),
+                _ => false,
+            };
+        if !matched {
+            return Precondition::Failed;
+        }
+    } else if let Some(date) = req
+        .get_header("If-Unmodified-Since")
+        .and_then(parse_http_date)
+    {
+        if validators
+            .modified_secs()
+            .is_some_and(|modified| modified > date.timestamp())
+        {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(value) = req.get_header("If-None-Match") {
+        let matched = value.trim() == "*"
+            || match (&validators.etag, EntityTag::parse_list(value)) {
+                (Some(etag), Some(tags)) => tags.iter().any(|t| t.weak_eq(etag)),
+                _ => false,
+            };
+        if matched {
+            return if is_read {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if is_read {
+        if let Some(date) = req
+            .get_header("If-Modified-Since")
+            .and_then(parse_http_date)
+        {
+            if validators
+                .modified_secs()
+                .is_some_and(|modified| modified <= date.timestamp())
+            {
+                return Precondition::NotModified;
+            }
+        }
+    }
+    Precondition::Proceed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_etags() {
+        let tags = EntityTag::parse_list(r#""a", W/"b" ,,"""#).unwrap();
+        assert_eq!(tags.len(), 3);
+        assert!(!tags[0].weak && tags[1].weak && tags[2].tag.is_empty());
+        assert!(tags[1].weak_eq(&tags[1]) && !tags[1].strong_eq(&tags[1]));
+        assert_eq!(tags[1].to_string(), r#"W/"b""#);
+        assert!(EntityTag::parse_list(r#""a" "b""#).is_none());
+        assert!(EntityTag::parse_list("a").is_none());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let modified = Utc.ymd(2024, 5, 1).and_hms(12, 0, 0);
+        let validators = Validators::for_file(10, Some(modified), false);
+        let etag = validators.etag.clone().unwrap().to_string();
+        let eval = |method, headers: &[(&str, &str)]| {
+            evaluate(&Request::for_test(method, "/", headers), &validators)
+        };
+        use Precondition::*;
+
+        assert_eq!(eval(Method::Get, &[]), Proceed);
+        assert_eq!(eval(Method::Get, &[("If-None-Match", &etag)]), NotModified);
+        assert_eq!(
+            eval(Method::Get, &[("If-None-Match", &format!("W/{}", etag))]),
+            NotModified
+        );
+        assert_eq!(eval(Method::Put, &[("If-None-Match", "*")]), Failed);
+        assert_eq!(
+            eval(Method::Get, &[("If-None-Match", r#""other""#)]),
+            Proceed
+        );
+
+        let after = "Wed, 01 May 2024 12:00:00 GMT";
+        let before = "Wed, 01 May 2024 11:59:59 GMT";
+        assert_eq!(
+            eval(Method::Get, &[("If-Modified-Since", after)]),
+            NotModified
+        );
+        assert_eq!(eval(Method::Get, &[("If-Modified-Since", before)]), Proceed);
+        // If-None-Match takes precedence over If-Modified-Since
+        let both = [
+            ("If-None-Match", r#""other""#),
+            ("If-Modified-Since", after),
+        ];
+        assert_eq!(eval(Method::Get, &both), Proceed);
+
+        assert_eq!(eval(Method::Put, &[("If-Match", &etag)]), Proceed);
+        assert_eq!(
+            eval(Method::Put, &[("If-Match", &format!("W/{}", etag))]),
+            Failed
+        );
+        assert_eq!(
+            eval(Method::Put, &[("If-Unmodified-Since", before)]),
+            Failed
+        );
+        assert_eq!(
+            eval(Method::Put, &[("If-Unmodified-Since", "garbage")]),
+            Proceed
+        );
+        // If-Match takes precedence over If-Unmodified-Since
+        let both = [("If-Match", "*"), ("If-Unmodified-Since", before)];
+        assert_eq!(eval(Method::Put, &both), Proceed);
+
+        let now = modified + chrono::Duration::seconds(1);
+        assert!(validators.if_range_matches(&etag, now));
+        assert!(validators.if_range_matches(after, now));
+        assert!(!validators.if_range_matches(before, now));
+        let weak = Validators::for_file(10, Some(modified), true);
+        assert!(!weak.if_range_matches(&etag, now));
+        // modified within the second the response is sent, the date is weak
+        assert!(!validators.if_range_matches(after, modified));
+        assert!(validators.if_range_matches(&etag, modified));
+    }
+}
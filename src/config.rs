@@ -1,5 +1,6 @@
-use anyhow::Error;
+use crate::error::Error;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -9,6 +10,110 @@ pub struct Config {
     pub port: u16,
     pub directory: String,
     pub tls: Option<Tls>,
+    /// Methods the server answers out of GET, HEAD and OPTIONS, all by default
+    pub methods: Option<Vec<String>>,
+    /// Seconds an idle persistent connection is kept open
+    pub keep_alive_timeout: Option<u64>,
+    /// Requests served on one connection before it is closed
+    pub max_requests: Option<usize>,
+    /// Longest request line accepted, in bytes
+    pub max_request_line: Option<usize>,
+    /// Largest header section accepted, in bytes
+    pub max_header_bytes: Option<usize>,
+    /// Most header fields accepted in one request
+    pub max_headers: Option<usize>,
+    /// Largest request body accepted, in bytes
+    pub max_body_size: Option<u64>,
+    /// Send weak entity tags for files, for content that may change
+    /// without its length or modification time changing
+    pub weak_etags: Option<bool>,
+    /// Compression of responses, on with defaults when left out
+    pub compression: Option<Compression>,
+    /// Headers set on responses to matching paths, in order
+    pub rules: Option<Vec<Rule>>,
+    /// Security headers sent with every response
+    pub security_headers: Option<SecurityHeaders>,
+    /// CORS policies, the first one matching the path applies
+    pub cors: Option<Vec<Cors>>,
+    /// Symbolic links followed under `directory`: follow, within_root or
+    /// deny, defaults to within_root
+    pub symlinks: Option<String>,
+}
+
+/// Compression config
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Compression {
+    pub enabled: Option<bool>,
+    /// Smallest file compressed on the fly, in bytes
+    pub min_size: Option<u64>,
+    /// Largest file compressed on the fly, in bytes
+    pub max_size: Option<u64>,
+    /// Media types compressed on the fly, `text/*` matches every text type
+    pub types: Option<Vec<String>>,
+    /// Codings offered in order of preference, out of br, zstd and gzip
+    pub encodings: Option<Vec<String>>,
+}
+
+/// Response header rule
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// Glob over the request path, as `/static/**` or `**/*.html`
+    pub path: Option<String>,
+    /// File extensions the rule is limited to
+    pub extensions: Option<Vec<String>>,
+    pub cache_control: Option<String>,
+    /// Seconds after the response the Expires header is set to
+    pub expires: Option<u64>,
+    /// Any other headers to set
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+/// Security headers config
+/// an empty string turns off a header the preset sends
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SecurityHeaders {
+    /// none, basic or strict, defaults to basic
+    pub preset: Option<String>,
+    /// Seconds of Strict-Transport-Security, 0 turns it off
+    pub hsts_max_age: Option<u64>,
+    pub hsts_include_subdomains: Option<bool>,
+    pub hsts_preload: Option<bool>,
+    pub content_security_policy: Option<String>,
+    /// Policies for matching paths, the first match wins
+    pub csp_overrides: Option<Vec<CspOverride>>,
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub cross_origin_opener_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+/// Content security policy for the paths a glob matches
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CspOverride {
+    pub path: String,
+    /// empty for no policy
+    pub policy: String,
+}
+
+/// CORS policy config
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cors {
+    /// Glob over the request path, all paths when left out
+    pub path: Option<String>,
+    /// Exact origins, `*` for any, or with wildcards as `https://*.example.com`
+    pub origins: Option<Vec<String>>,
+    /// Regexes an origin has to match whole
+    pub origin_patterns: Option<Vec<String>>,
+    /// Methods allowed, defaults to GET, HEAD and POST
+    pub methods: Option<Vec<String>>,
+    /// Request headers allowed, `*` for any
+    pub headers: Option<Vec<String>>,
+    /// Response headers scripts may read
+    pub expose_headers: Option<Vec<String>>,
+    pub credentials: Option<bool>,
+    /// Seconds a preflight may be cached
+    pub max_age: Option<u64>,
 }
 
 /// TLS config
@@ -22,8 +127,8 @@ impl TryFrom<&str> for Config {
     type Error = Error;
 
     fn try_from(value: &str) -> Result<Config, Self::Error> {
-        let content = fs::read_to_string(value).unwrap();
-        let cfg: Config = serde_yaml::from_str(&content).unwrap();
-        Ok(cfg)
+        let invalid = |reason: String| Error::Config(format!("{}: {}", value, reason));
+        let content = fs::read_to_string(value).map_err(|e| invalid(e.to_string()))?;
+        serde_yaml::from_str(&content).map_err(|e| invalid(e.to_string()))
     }
 }
@@ -0,0 +1,358 @@
+use crate::config;
+use crate::http::Method;
+use crate::request::Request;
+use crate::rules::glob_match;
+use regex::Regex;
+
+/// Methods allowed cross-origin when the config does not list any
+static DEFAULT_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+
+/// Origins a policy lets read its responses
+#[derive(Debug, Clone)]
+enum Origins {
+    /// any origin, sent as `*` and never with credentials
+    Any,
+    /// exact origins, and patterns for wildcard and regex ones
+    Listed {
+        exact: Vec<String>,
+        patterns: Vec<Regex>,
+    },
+}
+
+impl Origins {
+    fn allows(&self, origin: &str) -> bool {
+        match self {
+            Origins::Any => true,
+            Origins::Listed { exact, patterns } => {
+                exact.iter().any(|o| o.eq_ignore_ascii_case(origin))
+                    || patterns.iter().any(|p| p.is_match(origin))
+            }
+        }
+    }
+}
+
+/// A CORS policy for the paths a glob matches
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// glob over the request path, all paths when left out
+    path: Option<String>,
+    origins: Origins,
+    methods: Vec<String>,
+    /// request headers allowed, None allows any
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl Policy {
+    /// Builds a policy from the config
+    /// origins that don't compile are left out with a warning, and so are
+    /// credentials for any origin, which would let every site act as the user
+    pub fn from_config(cfg: &config::Cors) -> Self {
+        let listed = cfg.origins.iter().flatten();
+        let origins = if listed.clone().any(|o| o == "*") {
+            Origins::Any
+        } else {
+            let mut exact = Vec::new();
+            let mut patterns = Vec::new();
+            for origin in listed {
+                if origin.contains('*') {
+                    patterns.extend(compile(&wildcard_regex(origin)));
+                } else {
+                    exact.push(origin.clone());
+                }
+            }
+            for pattern in cfg.origin_patterns.iter().flatten() {
+                patterns.extend(compile(&format!("^(?:{})$", pattern)));
+            }
+            Origins::Listed { exact, patterns }
+        };
+
+        let mut credentials = cfg.credentials.unwrap_or(false);
+        if credentials && matches!(origins, Origins::Any) {
+            eprintln!("Ignoring CORS credentials, they need origins to be listed rather than *");
+            credentials = false;
+        }
+
+        let headers = match &cfg.headers {
+            Some(headers) if headers.iter().any(|h| h == "*") => None,
+            Some(headers) => Some(headers.clone()),
+            None => Some(Vec::new()),
+        };
+        Self {
+            path: cfg
+                .path
+                .as_ref()
+                .map(|p| p.trim_start_matches('/').to_string()),
+            origins,
+            methods: match &cfg.methods {
+                Some(methods) => methods.iter().map(|m| m.to_ascii_uppercase()).collect(),
+                None => DEFAULT_METHODS.iter().map(|m| m.to_string()).collect(),
+            },
+            headers,
+            expose_headers: cfg.expose_headers.clone().unwrap_or_default(),
+            credentials,
+            max_age: cfg.max_age,
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match &self.path {
+            Some(glob) => glob_match(glob, path.trim_start_matches('/')),
+            None => true,
+        }
+    }
+
+    /// Access-Control-Allow-Origin for an allowed origin
+    fn allow_origin(&self, origin: &str) -> String {
+        match self.origins {
+            Origins::Any => "*".to_string(),
+            Origins::Listed { .. } => origin.to_string(),
+        }
+    }
+
+    /// Checks if responses to the policy's paths depend on the Origin
+    fn varies(&self) -> bool {
+        !matches!(self.origins, Origins::Any)
+    }
+
+    fn allows_headers(&self, requested: &[&str]) -> bool {
+        match &self.headers {
+            None => true,
+            Some(allowed) => requested
+                .iter()
+                .all(|r| allowed.iter().any(|a| a.eq_ignore_ascii_case(r))),
+        }
+    }
+}
+
+/// Translates an origin with `*` wildcards into an anchored regex
+/// a wildcard stands for one or more characters of a host name
+fn wildcard_regex(origin: &str) -> String {
+    let parts: Vec<String> = origin.split('*').map(regex::escape).collect();
+    format!(
+        "^(?i:{})$",
+        parts.join("[A-Za-z0-9-]+(?:\\.[A-Za-z0-9-]+)*")
+    )
+}
+
+fn compile(pattern: &str) -> Option<Regex> {
+    Regex::new(pattern)
+        .map_err(|e| eprintln!("Ignoring invalid CORS origin {}: {}", pattern, e))
+        .ok()
+}
+
+/// CORS policies of a server, the first one matching the path applies
+#[derive(Debug, Clone, Default)]
+pub struct Cors(Vec<Policy>);
+
+impl Cors {
+    pub fn from_config(cfg: Option<&Vec<config::Cors>>) -> Self {
+        Self(
+            cfg.iter()
+                .flat_map(|policies| policies.iter())
+                .map(Policy::from_config)
+                .collect(),
+        )
+    }
+
+    fn policy(&self, req: &Request) -> Option<&Policy> {
+        self.0.iter().find(|p| p.matches(&req.uri().path))
+    }
+
+    /// Checks if the request is a preflight the server answers itself
+    pub fn is_preflight(&self, req: &Request) -> bool {
+        req.method() == Method::Options
+            && req.get_header("Origin").is_some()
+            && req.get_header("Access-Control-Request-Method").is_some()
+            && self.policy(req).is_some()
+    }
+
+    /// Headers answering a preflight request (Fetch section 3.2.3)
+    ///
+    /// A preflight asking for a method or headers the policy doesn't allow
+    /// gets no Access-Control headers, which fails it in the browser.
+    pub fn preflight_headers(&self, req: &Request) -> Vec<(&'static str, String)> {
+        let mut headers = vec![(
+            "Vary",
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers".to_string(),
+        )];
+        let policy = match self.policy(req) {
+            Some(policy) => policy,
+            None => return headers,
+        };
+        let origin = req.get_header("Origin").unwrap_or("");
+        let method = req
+            .get_header("Access-Control-Request-Method")
+            .unwrap_or("");
+        let requested: Vec<&str> = req
+            .get_header("Access-Control-Request-Headers")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .collect();
+        if !policy.origins.allows(origin)
+            || !policy.methods.iter().any(|m| m == method)
+            || !policy.allows_headers(&requested)
+        {
+            return headers;
+        }
+
+        headers.push(("Access-Control-Allow-Origin", policy.allow_origin(origin)));
+        if policy.credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        headers.push(("Access-Control-Allow-Methods", policy.methods.join(", ")));
+        if !requested.is_empty() {
+            // the headers asked for are all allowed, so they are echoed
+            headers.push(("Access-Control-Allow-Headers", requested.join(", ")));
+        }
+        if let Some(max_age) = policy.max_age {
+            headers.push(("Access-Control-Max-Age", max_age.to_string()));
+        }
+        headers
+    }
+
+    /// Headers for the response to an actual request
+    /// responses that differ by origin are marked with `Vary: Origin`
+    pub fn headers(&self, req: &Request) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        let policy = match self.policy(req) {
+            Some(policy) => policy,
+            None => return headers,
+        };
+        if policy.varies() {
+            headers.push(("Vary", "Origin".to_string()));
+        }
+        let origin = match req.get_header("Origin") {
+            Some(origin) if policy.origins.allows(origin) => origin,
+            _ => return headers,
+        };
+
+        headers.push(("Access-Control-Allow-Origin", policy.allow_origin(origin)));
+        if policy.credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        if !policy.expose_headers.is_empty() {
+            headers.push((
+                "Access-Control-Expose-Headers",
+                policy.expose_headers.join(", "),
+            ));
+        }
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(headers: &'a [(&str, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_origins() {
+        let cfg = config::Cors {
+            origins: Some(vec![
+                "https://app.example.com".to_string(),
+                "https://*.example.org".to_string(),
+            ]),
+            origin_patterns: Some(vec![r"http://localhost:\d+".to_string()]),
+            ..Default::default()
+        };
+        let origins = Policy::from_config(&cfg).origins;
+        assert!(origins.allows("https://app.example.com"));
+        assert!(origins.allows("https://a.b.example.org"));
+        assert!(origins.allows("http://localhost:3000"));
+        assert!(!origins.allows("https://example.org"));
+        assert!(!origins.allows("https://evil.com/.example.org"));
+        assert!(!origins.allows("http://localhost:3000.evil.com"));
+        assert!(!origins.allows("https://app.example.com.evil.com"));
+    }
+
+    #[test]
+    fn test_cors_headers() {
+        let cors = Cors::from_config(Some(&vec![config::Cors {
+            path: Some("/api/**".to_string()),
+            origins: Some(vec!["https://app.example.com".to_string()]),
+            methods: Some(vec!["GET".to_string(), "PUT".to_string()]),
+            headers: Some(vec!["Content-Type".to_string()]),
+            credentials: Some(true),
+            max_age: Some(600),
+            ..Default::default()
+        }]));
+        let origin = ("Origin", "https://app.example.com");
+
+        let preflight = Request::for_test(
+            Method::Options,
+            "/api/items",
+            &[
+                origin,
+                ("Access-Control-Request-Method", "PUT"),
+                ("Access-Control-Request-Headers", "content-type"),
+            ],
+        );
+        assert!(cors.is_preflight(&preflight));
+        let headers = cors.preflight_headers(&preflight);
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Methods"),
+            Some("GET, PUT")
+        );
+        assert_eq!(header(&headers, "Access-Control-Max-Age"), Some("600"));
+
+        let denied = Request::for_test(
+            Method::Options,
+            "/api/items",
+            &[origin, ("Access-Control-Request-Method", "DELETE")],
+        );
+        assert_eq!(
+            header(
+                &cors.preflight_headers(&denied),
+                "Access-Control-Allow-Origin"
+            ),
+            None
+        );
+
+        let get = Request::for_test(Method::Get, "/api/items", &[origin]);
+        let headers = cors.headers(&get);
+        assert_eq!(header(&headers, "Vary"), Some("Origin"));
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Credentials"),
+            Some("true")
+        );
+
+        let other = Request::for_test(Method::Get, "/api/items", &[("Origin", "https://evil.com")]);
+        let headers = cors.headers(&other);
+        assert_eq!(header(&headers, "Vary"), Some("Origin"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), None);
+
+        assert!(cors
+            .headers(&Request::for_test(Method::Get, "/index.html", &[origin]))
+            .is_empty());
+
+        // credentials for any origin would reflect every origin, so they go
+        let any = Cors::from_config(Some(&vec![config::Cors {
+            origins: Some(vec!["*".to_string()]),
+            credentials: Some(true),
+            ..Default::default()
+        }]));
+        let headers = any.headers(&Request::for_test(
+            Method::Get,
+            "/",
+            &[("Origin", "https://evil.com")],
+        ));
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&headers, "Access-Control-Allow-Credentials"), None);
+        assert_eq!(header(&headers, "Vary"), None);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::config;
+use std::io::{self, Read};
+
+/// Smallest file compressed on the fly, smaller ones gain too little
+const DEFAULT_MIN_SIZE: u64 = 1024;
+/// Largest file compressed on the fly
+const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// Media types compressed on the fly unless the config lists others
+const DEFAULT_TYPES: [&str; 7] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+    "image/x-icon",
+];
+
+/// A content coding the server can send (RFC 9110 section 8.4.1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    /// Every coding, in the order they are preferred
+    pub const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    /// Coding name as sent in Accept-Encoding and Content-Encoding
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of a precompressed copy of a file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            // x-gzip is an alias kept for old clients
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Compresses the content as it is read
+    /// levels favour speed, the content is compressed on every request
+    pub fn encode(&self, content: Box<dyn Read + Send>) -> io::Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Encoding::Brotli => Box::new(brotli::CompressorReader::new(content, 8192, 5, 22)),
+            Encoding::Zstd => Box::new(zstd::stream::read::Encoder::new(content, 3)?),
+            Encoding::Gzip => Box::new(flate2::read::GzEncoder::new(
+                content,
+                flate2::Compression::new(6),
+            )),
+        })
+    }
+}
+
+/// Picks the coding to send from the ones offered, in the order offered
+///
+/// Codings the client gives the highest quality win, ties go to the
+/// earliest offered. A coding the client doesn't list is only acceptable
+/// through a `*` entry. None means the content is sent as is.
+pub fn negotiate(accept: Option<&str>, offered: &[Encoding]) -> Option<Encoding> {
+    let accept = accept?;
+    let mut qualities = Vec::new();
+    let mut any = None;
+    for member in accept.split(',') {
+        let mut params = member.split(';').map(str::trim);
+        let coding = params.next().unwrap_or("");
+        if coding.is_empty() {
+            continue;
+        }
+        // a malformed weight disables the coding rather than guessing
+        let q = params
+            .find_map(|p| {
+                let (name, value) = p.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+            })
+            .unwrap_or(1.0);
+        if coding == "*" {
+            any = Some(q);
+        } else if let Some(encoding) = Encoding::from_token(coding) {
+            qualities.push((encoding, q));
+        }
+    }
+
+    let quality = |encoding: &Encoding| {
+        qualities
+            .iter()
+            .find(|(e, _)| e == encoding)
+            .map(|(_, q)| *q)
+            .or(any)
+            .unwrap_or(0.0)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in offered {
+        let q = quality(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Which responses are compressed and how, built from the config
+#[derive(Debug, Clone)]
+pub struct Compression {
+    pub enabled: bool,
+    pub min_size: u64,
+    pub max_size: u64,
+    /// media types, `type/*` matches a whole top level type
+    pub types: Vec<String>,
+    /// codings offered, in order of preference
+    pub encodings: Vec<Encoding>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: DEFAULT_MIN_SIZE,
+            max_size: DEFAULT_MAX_SIZE,
+            types: DEFAULT_TYPES.iter().map(|t| t.to_string()).collect(),
+            encodings: Encoding::ALL.to_vec(),
+        }
+    }
+}
+
+impl Compression {
+    pub fn from_config(cfg: Option<&config::Compression>) -> Self {
+        let defaults = Self::default();
+        let cfg = match cfg {
+            Some(cfg) => cfg,
+            None => return defaults,
+        };
+        Self {
+            enabled: cfg.enabled.unwrap_or(defaults.enabled),
+            min_size: cfg.min_size.unwrap_or(defaults.min_size),
+            max_size: cfg.max_size.unwrap_or(defaults.max_size),
+            types: cfg.types.clone().unwrap_or(defaults.types),
+            // unknown codings in the config are left out
+            encodings: match &cfg.encodings {
+                Some(names) => names
+                    .iter()
+                    .filter_map(|name| Encoding::from_token(name))
+                    .collect(),
+                None => defaults.encodings,
+            },
+        }
+    }
+
+    /// Codings a precompressed copy of a file may be sent in
+    pub fn offered(&self) -> &[Encoding] {
+        if self.enabled {
+            &self.encodings
+        } else {
+            &[]
+        }
+    }
+
+    /// Checks if content of the type and length is compressed on the fly
+    pub fn compresses(&self, content_type: &str, length: u64) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        self.enabled
+            && (self.min_size..=self.max_size).contains(&length)
+            && self.types.iter().any(|t| match t.strip_suffix("/*") {
+                Some(top) => media_type
+                    .split_once('/')
+                    .is_some_and(|(media_top, _)| media_top.eq_ignore_ascii_case(top)),
+                None => t.eq_ignore_ascii_case(&media_type),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Encoding::*;
+
+    #[test]
+    fn test_negotiate() {
+        let all = &Encoding::ALL;
+        assert_eq!(negotiate(None, all), None);
+        assert_eq!(
+            negotiate(Some("gzip, deflate, br, zstd"), all),
+            Some(Brotli)
+        );
+        assert_eq!(negotiate(Some("gzip, br;q=0.5"), all), Some(Gzip));
+        assert_eq!(negotiate(Some("x-gzip"), all), Some(Gzip));
+        assert_eq!(negotiate(Some("br;q=0, *"), all), Some(Zstd));
+        assert_eq!(negotiate(Some("*;q=0"), all), None);
+        assert_eq!(negotiate(Some("identity"), all), None);
+        assert_eq!(negotiate(Some("gzip;q=oops, zstd"), all), Some(Zstd));
+        assert_eq!(negotiate(Some("br, gzip"), &[Gzip]), Some(Gzip));
+    }
+
+    #[test]
+    fn test_compresses() {
+        let compression = Compression::default();
+        assert!(compression.compresses("text/html; charset=utf-8", 5000));
+        assert!(compression.compresses("application/json", 5000));
+        assert!(!compression.compresses("image/png", 5000));
+        assert!(!compression.compresses("text/plain", 10));
+    }
+
+    #[test]
+    fn test_encode() {
+        let content = "fimafeng ".repeat(1000);
+        for encoding in Encoding::ALL {
+            let mut encoded = Vec::new();
+            encoding
+                .encode(Box::new(io::Cursor::new(content.clone().into_bytes())))
+                .unwrap()
+                .read_to_end(&mut encoded)
+                .unwrap();
+            assert!(encoded.len() < content.len() / 10);
+
+            let mut decoded = String::new();
+            match encoding {
+                Brotli => {
+                    brotli::Decompressor::new(encoded.as_slice(), 4096).read_to_string(&mut decoded)
+                }
+                Zstd => zstd::stream::read::Decoder::new(encoded.as_slice())
+                    .unwrap()
+                    .read_to_string(&mut decoded),
+                Gzip => {
+                    flate2::read::GzDecoder::new(encoded.as_slice()).read_to_string(&mut decoded)
+                }
+            }
+            .unwrap();
+            assert_eq!(decoded, content);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::http::StatusCode;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// Result of reading and answering a request
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Failures while reading and answering a request
+///
+/// Every variant but `Io` and `Config` maps to the status the client is
+/// answered with.
+#[derive(Debug)]
+pub enum Error {
+    /// The request is malformed
+    BadRequest(String),
+    /// The target exists but may not be served
+    Forbidden(String),
+    /// The target does not exist
+    NotFound(String),
+    /// The client stopped sending in the middle of a request
+    Timeout,
+    /// The body is larger than the server accepts
+    PayloadTooLarge,
+    /// The request target is longer than the server accepts
+    UriTooLong,
+    /// The header section is larger than the server accepts
+    HeadersTooLarge,
+    /// The request has an Expect header the server can't meet
+    ExpectationFailed,
+    /// The request line names an HTTP version the server does not speak
+    UnsupportedVersion,
+    /// The request needs a feature the server does not have
+    NotImplemented(String),
+    /// The server failed to build the response
+    Internal(String),
+    /// The connection itself failed, nothing can be sent back
+    Io(io::Error),
+    /// The server can't start with its config or the files it names
+    Config(String),
+}
+
+impl Error {
+    /// Status code of the response reporting the error
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Timeout => StatusCode::REQUEST_TIMEOUT,
+            Error::PayloadTooLarge => StatusCode::CONTENT_TOO_LARGE,
+            Error::UriTooLong => StatusCode::URI_TOO_LONG,
+            Error::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            Error::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Error::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Error::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            Error::Internal(_) | Error::Io(_) | Error::Config(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Maps a filesystem error on `path` to the status it should produce
+    /// unlike `From<io::Error>` this is not a connection failure
+    pub fn from_fs(e: io::Error, path: &str) -> Self {
+        match e.kind() {
+            // a file named as a directory on the way to the target
+            ErrorKind::NotFound | ErrorKind::NotADirectory => Error::NotFound(path.to_string()),
+            ErrorKind::PermissionDenied => Error::Forbidden(path.to_string()),
+            _ => Error::Internal(format!("{}: {}", path, e)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            Error::Forbidden(path) => write!(f, "forbidden: {}", path),
+            Error::NotFound(path) => write!(f, "not found: {}", path),
+            Error::Timeout => f.write_str("timed out reading request"),
+            Error::PayloadTooLarge => f.write_str("request body too large"),
+            Error::UriTooLong => f.write_str("request target too long"),
+            Error::HeadersTooLarge => f.write_str("request header fields too large"),
+            Error::ExpectationFailed => f.write_str("unsupported expectation"),
+            Error::NotImplemented(what) => write!(f, "not implemented: {}", what),
+            Error::UnsupportedVersion => f.write_str("unsupported HTTP version"),
+            Error::Internal(reason) => write!(f, "internal error: {}", reason),
+            Error::Io(e) => write!(f, "connection error: {}", e),
+            Error::Config(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<tinytemplate::error::Error> for Error {
+    fn from(e: tinytemplate::error::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
@@ -1,5 +1,8 @@
-use anyhow::Error;
+use crate::encoding::Encoding;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
 use std::fs;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use tinytemplate::TinyTemplate;
 
@@ -13,97 +16,194 @@ struct FilesContext {
     rows: Vec<String>,
 }
 
-/// A file with its relevant metadata
+/// An open file with its relevant metadata
+/// the content is read from the handle as it is sent
 pub struct File {
-    pub content: String,
+    pub handle: fs::File,
     pub content_length: u64,
     pub content_type: String,
+    /// last modification time, if the platform keeps one
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// A page rendered from a template
+pub struct Page {
+    pub content: String,
+    pub content_type: String,
+}
+
+/// Which symbolic links under the served directory are followed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// any link, wherever it points
+    Follow,
+    /// links that point inside the served directory
+    WithinRoot,
+    /// no links, a path through one is forbidden
+    Deny,
 }
 
-impl File {
-    pub fn new(content_length: u64, content_type: &str, content: &str) -> File {
-        Self {
-            content: content.to_string(),
-            content_length,
-            content_type: content_type.to_string(),
+impl SymlinkPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "follow" => Some(SymlinkPolicy::Follow),
+            "within_root" => Some(SymlinkPolicy::WithinRoot),
+            "deny" => Some(SymlinkPolicy::Deny),
+            _ => None,
         }
     }
 }
 
 /// Manages files in the served directory
+///
+/// Request targets are resolved under `web_dir` only. The 404 page and the
+/// listing template are the server's own and stay relative to the working
+/// directory.
 #[derive(Clone)]
 pub struct FileManager {
+    /// canonical path of the served directory
     web_dir: PathBuf,
-    listed_files: Vec<PathBuf>,
+    symlinks: SymlinkPolicy,
 }
 
 impl FileManager {
-    pub fn new(dir: &str) -> Self {
-        Self {
-            web_dir: PathBuf::from(dir),
-            listed_files: FileManager::read_dir(dir),
-        }
+    pub fn new(dir: &str, symlinks: SymlinkPolicy) -> Self {
+        let web_dir = fs::canonicalize(dir).unwrap_or_else(|e| {
+            eprintln!("Failed to resolve directory {}: {}", dir, e);
+            PathBuf::from(dir)
+        });
+        Self { web_dir, symlinks }
     }
 
     /// Path
     pub fn base_path(&self) -> String {
-        self.web_dir.to_str().unwrap().to_string()
+        self.web_dir.to_string_lossy().to_string()
+    }
+
+    /// Resolves a request target to a path under the served directory
+    ///
+    /// `..` segments are forbidden outright rather than resolved, so a
+    /// target can only leave the directory through a symbolic link, which
+    /// the symlink policy then decides on.
+    pub fn resolve(&self, name: &str) -> Result<PathBuf> {
+        let forbidden = || Error::Forbidden(name.to_string());
+        let mut path = self.web_dir.clone();
+        for segment in name.split('/').filter(|s| !s.is_empty() && *s != ".") {
+            if segment == ".." {
+                return Err(forbidden());
+            }
+            path.push(segment);
+            if self.symlinks == SymlinkPolicy::Deny {
+                let metadata = fs::symlink_metadata(&path).map_err(|e| Error::from_fs(e, name))?;
+                if metadata.file_type().is_symlink() {
+                    return Err(forbidden());
+                }
+            }
+        }
+
+        let resolved = fs::canonicalize(&path).map_err(|e| Error::from_fs(e, name))?;
+        if self.symlinks != SymlinkPolicy::Follow && !resolved.starts_with(&self.web_dir) {
+            return Err(forbidden());
+        }
+        Ok(resolved)
     }
 
     /// Checks if a file exists
     pub fn file_exist(&self, name: &str) -> bool {
-        fs::read(name).is_ok()
+        self.resolve(name).is_ok()
+    }
+
+    /// Opens a file under the served directory for sending, along with
+    /// its metadata
+    pub fn get_file(&self, name: &str) -> Result<File> {
+        let path = self.resolve(name)?;
+        FileManager::open(&path, name)
+    }
+
+    /// Opens a file at a resolved path, typed by the name it was asked for
+    fn open(path: &Path, name: &str) -> Result<File> {
+        let handle = fs::File::open(path).map_err(|e| Error::from_fs(e, name))?;
+        let metadata = handle.metadata().map_err(|e| Error::from_fs(e, name))?;
+        if !metadata.is_file() {
+            return Err(Error::NotFound(name.to_string()));
+        }
+
+        Ok(File {
+            handle,
+            content_length: metadata.len(),
+            content_type: FileManager::get_content_type(name),
+            modified: metadata.modified().ok().map(DateTime::from),
+        })
     }
 
-    /// Returns a file content and metadata
-    pub fn get_file(&self, name: &str) -> Result<File, Error> {
-        let content = fs::read_to_string(name).expect("file read failed");
-        let metadata = fs::metadata(name)?;
-        let content_type = FileManager::get_content_type(name);
-        let content_length = metadata.len();
+    /// Codings out of `encodings` a precompressed copy of the file exists in
+    /// the copies sit next to the file, as `app.js.br` for `app.js`
+    pub fn precompressed(&self, name: &str, encodings: &[Encoding]) -> Vec<Encoding> {
+        encodings
+            .iter()
+            .filter(|e| {
+                self.resolve(&format!("{}.{}", name, e.extension()))
+                    .and_then(|path| fs::metadata(path).map_err(|e| Error::from_fs(e, name)))
+                    .is_ok_and(|m| m.is_file())
+            })
+            .copied()
+            .collect()
+    }
 
-        Ok(File::new(
-            content_length,
-            content_type.as_str(),
-            content.as_str(),
-        ))
+    /// Opens the precompressed copy of a file, typed as the file itself
+    pub fn get_encoded(&self, name: &str, encoding: Encoding) -> Result<File> {
+        let file = self.get_file(&format!("{}.{}", name, encoding.extension()))?;
+        Ok(File {
+            content_type: FileManager::get_content_type(name),
+            ..file
+        })
     }
 
-    pub fn template_dir(&self, dir_name: &str) -> Result<File, Error> {
-        let file = self.get_file(INDEX_PAGE).unwrap();
+    pub fn template_dir(&self, dir_name: &str) -> Result<Page> {
+        // a missing listing template is a server fault, not a missing target
+        let template = fs::read_to_string(INDEX_PAGE).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::Internal(format!("missing template {}", INDEX_PAGE)),
+            _ => Error::from_fs(e, INDEX_PAGE),
+        })?;
         let mut tt = TinyTemplate::new();
-        tt.add_template("index", file.content.as_str()).unwrap();
+        tt.add_template("index", template.as_str())?;
 
-        let ld: Vec<String> = FileManager::read_dir(dir_name)
+        // rows are paths from the served directory, as requested
+        let dir = self.resolve(dir_name)?;
+        let ld: Vec<String> = FileManager::read_dir(&dir, dir_name)?
             .iter()
-            .map(|x| x.to_str().unwrap().to_string())
+            .filter_map(|x| x.file_name())
+            .map(|x| {
+                Path::new(dir_name.trim_matches('/'))
+                    .join(x)
+                    .to_string_lossy()
+                    .to_string()
+            })
             .collect();
 
         let ctx = FilesContext { rows: ld };
 
-        let content = tt.render("index", &ctx)?;
-        Ok(File {
-            content_length: content.len() as u64,
-            content,
-            content_type: file.content_type,
+        Ok(Page {
+            content: tt.render("index", &ctx)?,
+            content_type: FileManager::get_content_type(INDEX_PAGE),
         })
     }
 
     /// Returns the contents of the home page and its metadata
-    pub fn home(&self) -> Result<File, Error> {
+    pub fn home(&self) -> Result<File> {
         self.get_file(HOME_PAGE)
     }
 
     /// Returns the contents of the 404 page and its metadata
-    pub fn not_found(&self) -> Result<File, Error> {
-        self.get_file(NOT_FOUND)
+    pub fn not_found(&self) -> Result<File> {
+        FileManager::open(Path::new(NOT_FOUND), NOT_FOUND)
     }
 
     /// checks file extension and returns a mime type
     /// this is wrong!!!
     fn get_content_type(name: &str) -> String {
         let ext = match Path::new(name).extension() {
-            Some(x) => x.to_str().unwrap(),
+            Some(x) => x.to_str().unwrap_or(""),
             None => "",
         };
         match ext {
@@ -113,26 +213,71 @@ impl FileManager {
             "jpg" => "image/jpg".to_string(),
             "gif" => "image/gif".to_string(),
             "jpeg" => "image/jpeg".to_string(),
+            "svg" => "image/svg+xml".to_string(),
+            "webp" => "image/webp".to_string(),
+            "ico" => "image/x-icon".to_string(),
+            "css" => "text/css".to_string(),
+            "js" => "text/javascript".to_string(),
+            "wasm" => "application/wasm".to_string(),
+            "pdf" => "application/pdf".to_string(),
+            "zip" => "application/zip".to_string(),
+            "gz" => "application/gzip".to_string(),
             _ => "text/plain".to_string(),
         }
     }
 
     /// Digs deepers into a directory
-    pub fn is_dir(name: &str) -> bool {
-        let metadata = match fs::metadata(name) {
-            Ok(x) => x,
-            Err(_) => return false,
+    pub fn is_dir(&self, name: &str) -> bool {
+        let metadata = match self.resolve(name).map(fs::metadata) {
+            Ok(Ok(x)) => x,
+            _ => return false,
         };
         metadata.is_dir()
     }
 
     // Reads a directory
-    fn read_dir(name: &str) -> Vec<PathBuf> {
+    fn read_dir(path: &Path, name: &str) -> Result<Vec<PathBuf>> {
         let mut listed_files = Vec::new();
-        for entry in fs::read_dir(name).unwrap() {
-            let dir = entry.unwrap();
+        for entry in fs::read_dir(path).map_err(|e| Error::from_fs(e, name))? {
+            let dir = entry.map_err(|e| Error::from_fs(e, name))?;
             listed_files.push(dir.path());
         }
-        listed_files
+        Ok(listed_files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_resolve() {
+        let base = TempPath::new("resolve");
+        let root = base.join("root");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/a.txt"), "a").unwrap();
+        fs::write(base.join("secret.txt"), "s").unwrap();
+        symlink(root.join("dir/a.txt"), root.join("inside")).unwrap();
+        symlink(base.join("secret.txt"), root.join("outside")).unwrap();
+
+        let root_str = root.to_str().unwrap();
+        let fm = |policy| FileManager::new(root_str, policy);
+        let forbidden = |r: Result<PathBuf>| matches!(r, Err(Error::Forbidden(_)));
+        let within = fm(SymlinkPolicy::WithinRoot);
+        assert!(within.resolve("dir/a.txt").is_ok());
+        assert!(within.resolve("/dir/./a.txt").is_ok());
+        assert!(within.resolve("inside").is_ok());
+        assert!(forbidden(within.resolve("outside")));
+        assert!(forbidden(within.resolve("dir/../../secret.txt")));
+        assert!(matches!(within.resolve("missing"), Err(Error::NotFound(_))));
+        assert!(matches!(
+            within.resolve("dir/a.txt/b"),
+            Err(Error::NotFound(_))
+        ));
+
+        assert!(forbidden(fm(SymlinkPolicy::Deny).resolve("inside")));
+        assert!(fm(SymlinkPolicy::Follow).resolve("outside").is_ok());
     }
 }
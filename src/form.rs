@@ -0,0 +1,369 @@
+use std::io::{self, Read, Write};
+
+use crate::error::{Error, Result};
+use crate::http::{Headers, Params};
+use crate::parser::{parse_http_headers, parse_query};
+
+/// Largest header section of a single part
+const MAX_PART_HEAD: usize = 8 * 1024;
+
+/// Size of each read from the body
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Ends every line of the multipart framing
+const CRLF: &[u8] = b"\r\n";
+
+/// Fields and files of a form body
+#[derive(Debug, Default)]
+pub struct Form {
+    /// text fields, a name maps to every value sent for it in order
+    pub fields: Params,
+    /// file parts of a multipart body in the order they were sent
+    pub files: Vec<FilePart>,
+}
+
+impl Form {
+    /// First value sent for the field
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .get(name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    /// First file sent under the field name
+    pub fn file(&self, name: &str) -> Option<&FilePart> {
+        self.files.iter().find(|f| f.name == name)
+    }
+}
+
+/// A file sent in a multipart form
+#[derive(Debug)]
+pub struct FilePart {
+    /// name of the form field
+    pub name: String,
+    /// file name given by the client, not to be trusted as a path
+    pub filename: String,
+    pub content_type: String,
+    /// every header sent with the part
+    pub headers: Headers,
+    data: Vec<u8>,
+}
+
+impl FilePart {
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Content of the part
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Parses a form body according to its Content-Type
+/// returns None if the body is not a form
+pub fn parse(content_type: &str, mut body: impl Read) -> Result<Option<Form>> {
+    let (media_type, params) = parse_params(content_type);
+    if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        let mut query = Vec::new();
+        body.read_to_end(&mut query)?;
+        let fields = parse_query(&query)
+            .ok_or_else(|| Error::BadRequest("invalid form encoding".to_string()))?;
+        return Ok(Some(Form {
+            fields,
+            files: Vec::new(),
+        }));
+    }
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return Ok(None);
+    }
+
+    // RFC 2046 section 5.1.1 caps a boundary at 70 characters
+    let boundary = params
+        .into_iter()
+        .find(|(name, _)| name == "boundary")
+        .map(|(_, value)| value)
+        .filter(|b| (1..=70).contains(&b.len()))
+        .ok_or_else(|| Error::BadRequest("missing multipart boundary".to_string()))?;
+    Multipart::new(body, &boundary).parse().map(Some)
+}
+
+/// Splits a header value such as a media type into its main value and
+/// its `; name=value` parameters, with names lowercased and quoted
+/// values unescaped
+fn parse_params(value: &str) -> (&str, Vec<(String, String)>) {
+    let (main, mut rest) = value.split_once(';').unwrap_or((value, ""));
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ';']);
+        if rest.is_empty() {
+            return (main.trim(), params);
+        }
+        let name_end = rest.find(['=', ';']).unwrap_or(rest.len());
+        let name = rest[..name_end].trim().to_ascii_lowercase();
+        rest = &rest[name_end..];
+        let value = match rest.strip_prefix('=') {
+            Some(quoted) if quoted.starts_with('"') => {
+                let mut value = String::new();
+                let mut chars = quoted[1..].char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 2;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                rest = &quoted[end..];
+                value
+            }
+            Some(token) => {
+                let end = token.find(';').unwrap_or(token.len());
+                rest = &token[end..];
+                token[..end].trim().to_string()
+            }
+            None => String::new(),
+        };
+        params.push((name, value));
+    }
+}
+
+/// Reads a multipart body part by part (RFC 7578)
+struct Multipart<R> {
+    body: R,
+    buf: Vec<u8>,
+    /// CRLF and the dash-boundary that start every delimiter
+    delimiter: Vec<u8>,
+}
+
+impl<R: Read> Multipart<R> {
+    fn new(body: R, boundary: &str) -> Self {
+        Self {
+            body,
+            // the first delimiter may open the body without a CRLF before it
+            buf: CRLF.to_vec(),
+            delimiter: [b"\r\n--", boundary.as_bytes()].concat(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Form> {
+        let mut form = Form::default();
+        // the preamble before the first delimiter is ignored
+        self.copy_until_delimiter(&mut io::sink())?;
+
+        while self.next_part()? {
+            let headers = self.read_part_head()?;
+            let disposition = headers
+                .get("Content-Disposition")
+                .ok_or_else(|| invalid("part without Content-Disposition"))?;
+            let (kind, params) = parse_params(disposition);
+            if !kind.eq_ignore_ascii_case("form-data") {
+                return Err(invalid("part is not form-data"));
+            }
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.clone())
+            };
+            let name = param("name").ok_or_else(|| invalid("part without a name"))?;
+
+            match param("filename") {
+                Some(filename) => {
+                    let mut data = Vec::new();
+                    self.copy_until_delimiter(&mut data)?;
+                    let content_type = headers
+                        .get("Content-Type")
+                        .unwrap_or("application/octet-stream")
+                        .to_string();
+                    form.files.push(FilePart {
+                        name,
+                        filename,
+                        content_type,
+                        headers,
+                        data,
+                    });
+                }
+                None => {
+                    let mut value = Vec::new();
+                    self.copy_until_delimiter(&mut value)?;
+                    form.fields
+                        .entry(name)
+                        .or_default()
+                        .push(String::from_utf8(value).map_err(|_| invalid("field is not UTF-8"))?);
+                }
+            }
+        }
+        Ok(form)
+    }
+
+    /// Checks what follows a delimiter, false once it closed the body
+    /// the epilogue after the closing delimiter is ignored
+    fn next_part(&mut self) -> Result<bool> {
+        self.fill_to(2)?;
+        if self.buf.starts_with(b"--") {
+            return Ok(false);
+        }
+        // transport padding may follow the boundary
+        while self.fill_to(1)? && matches!(self.buf[0], b' ' | b'\t') {
+            self.buf.remove(0);
+        }
+        self.fill_to(CRLF.len())?;
+        if !self.buf.starts_with(CRLF) {
+            return Err(invalid("boundary not followed by CRLF"));
+        }
+        self.buf.drain(..CRLF.len());
+        Ok(true)
+    }
+
+    /// Reads the header section of a part up to its blank line
+    fn read_part_head(&mut self) -> Result<Headers> {
+        // a part without headers starts straight with the blank line
+        self.fill_to(CRLF.len())?;
+        if self.buf.starts_with(CRLF) {
+            self.buf.drain(..CRLF.len());
+            return Ok(Headers::new());
+        }
+        loop {
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                let end = pos + 4;
+                let (rest, headers) =
+                    parse_http_headers(&self.buf[..end]).map_err(|_| invalid("bad part header"))?;
+                if rest != CRLF {
+                    return Err(invalid("bad part header"));
+                }
+                self.buf.drain(..end);
+                return Ok(headers);
+            }
+            if self.buf.len() > MAX_PART_HEAD {
+                return Err(Error::HeadersTooLarge);
+            }
+            if self.fill()? == 0 {
+                return Err(invalid("body ended inside a part header"));
+            }
+        }
+    }
+
+    /// Moves part data to `out` up to the next delimiter, which is consumed
+    fn copy_until_delimiter(&mut self, out: &mut impl Write) -> Result<()> {
+        let write_error = |e: io::Error| Error::Internal(format!("storing form part: {}", e));
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                out.write_all(&self.buf[..pos]).map_err(write_error)?;
+                self.buf.drain(..pos + self.delimiter.len());
+                return out.flush().map_err(write_error);
+            }
+            // keep what could be the start of a delimiter split across reads
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let n = self.buf.len() - keep;
+                out.write_all(&self.buf[..n]).map_err(write_error)?;
+                self.buf.drain(..n);
+            }
+            if self.fill()? == 0 {
+                return Err(invalid("body ended before the closing boundary"));
+            }
+        }
+    }
+
+    /// Reads until at least `n` bytes are buffered, false if the body ends first
+    fn fill_to(&mut self, n: usize) -> Result<bool> {
+        while self.buf.len() < n {
+            if self.fill()? == 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Appends the next read from the body to the buffer
+    fn fill(&mut self) -> Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = self.body.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::BadRequest(format!("invalid multipart body: {}", reason))
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Trickle;
+
+    #[test]
+    fn test_parse_params() {
+        let (main, params) =
+            parse_params(r#"form-data; name="a \"b\";c"; filename=x.txt;Charset = utf-8"#);
+        assert_eq!(main, "form-data");
+        assert_eq!(
+            params,
+            vec![
+                ("name".to_string(), r#"a "b";c"#.to_string()),
+                ("filename".to_string(), "x.txt".to_string()),
+                ("charset".to_string(), "utf-8".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_urlencoded() {
+        let form = parse(
+            "application/x-www-form-urlencoded",
+            &b"a=1&b=x+y&a=%C3%A9"[..],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(form.fields["a"], vec!["1", "é"]);
+        assert_eq!(form.get("b"), Some("x y"));
+        assert!(parse("text/plain", &b"a=1"[..]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let body = b"preamble\r\n--XyZ\r\n\
+                     Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+                     hello\r\n--XyZ  \r\n\
+                     Content-Disposition: form-data; name=\"upload\"; filename=\"a.bin\"\r\n\
+                     Content-Type: application/x-thing\r\n\r\n\
+                     \x00\r\n--Xy\xff\r\n--XyZ--\r\nepilogue";
+        let form = parse(
+            "multipart/form-data; boundary=\"XyZ\"",
+            Trickle {
+                data: body,
+                step: 3,
+            },
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(form.get("title"), Some("hello"));
+        let file = form.file("upload").unwrap();
+        assert_eq!(file.filename, "a.bin");
+        assert_eq!(file.content_type, "application/x-thing");
+        assert_eq!(file.bytes(), b"\x00\r\n--Xy\xff");
+
+        let truncated = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1";
+        assert!(matches!(
+            parse("multipart/form-data; boundary=XyZ", &truncated[..]),
+            Err(Error::BadRequest(_))
+        ));
+        assert!(parse("multipart/form-data", &b""[..]).is_err());
+    }
+}
@@ -0,0 +1,1180 @@
+use crate::error::Error;
+use crate::hpack::{self, DecodeError, Decoder, Field};
+use crate::http::{HTTPVersion, Headers, Method, StatusCode};
+use crate::parser::{is_field_vchar, is_tchar};
+use crate::request::{Limits, Request};
+use crate::response::{Body, Response};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Sent by the client before its first frame
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+/// Length of a frame header
+const FRAME_HEADER_LEN: usize = 9;
+
+/// Flow control window every stream and the connection start with
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// Largest flow control window allowed
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Largest frame payload either side may send until told otherwise
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+/// Largest frame payload that can be advertised
+const MAX_FRAME_SIZE_LIMIT: usize = (1 << 24) - 1;
+
+/// Streams a client may have open at once
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+/// Largest header block accepted across HEADERS and CONTINUATION frames
+const MAX_HEADER_BLOCK: usize = 1 << 16;
+
+/// Size of each read from the stream
+const READ_CHUNK: usize = 16_384;
+
+/// Headers that only make sense on an HTTP/1.1 connection
+static CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// A request that asked to switch from HTTP/1.1 with `Upgrade: h2c`
+pub struct Upgrade {
+    pub request: Request,
+    /// payload of the HTTP2-Settings header
+    pub settings: Vec<u8>,
+}
+
+/// Failure while handling a frame
+enum H2Error {
+    /// ends the connection with GOAWAY
+    Connection(u32, &'static str),
+    /// resets a single stream with RST_STREAM
+    Stream(u32, u32),
+    Io(io::Error),
+}
+
+impl From<io::Error> for H2Error {
+    fn from(e: io::Error) -> Self {
+        H2Error::Io(e)
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Payload without the padding of a PADDED frame
+    fn data(&self) -> Result<&[u8], H2Error> {
+        if !self.has(PADDED) {
+            return Ok(&self.payload);
+        }
+        match self.payload.split_first() {
+            Some((&pad, rest)) if (pad as usize) <= rest.len() => {
+                Ok(&rest[..rest.len() - pad as usize])
+            }
+            _ => Err(H2Error::Connection(PROTOCOL_ERROR, "padding too long")),
+        }
+    }
+}
+
+/// Reads from a response body until a frame's worth is buffered
+/// returns true once the body has ended
+fn fill_frame(source: &mut dyn Read, buf: &mut Vec<u8>, size: usize) -> io::Result<bool> {
+    let mut chunk = vec![0; size];
+    while buf.len() < size {
+        match source.read(&mut chunk[..size - buf.len()]) {
+            Ok(0) => return Ok(true),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+/// State of one stream
+struct Stream {
+    /// request waiting for the end of its body
+    request: Option<Request>,
+    body: Vec<u8>,
+    /// the client sent END_STREAM
+    recv_closed: bool,
+    send_window: i64,
+    /// DATA the client may still send before the window is handed back
+    recv_window: i64,
+    /// response body still to be read, a frame at a time
+    source: Option<Box<dyn Read + Send>>,
+    /// bytes read from the source waiting for flow control window
+    pending: Vec<u8>,
+}
+
+/// Serves an HTTP/2 connection until the client goes away
+///
+/// `buffered` holds bytes already read off the stream, the connection
+/// preface included.
+pub fn serve<S: Read + Write>(
+    stream: S,
+    buffered: Vec<u8>,
+    upgrade: Option<Upgrade>,
+    limits: &Limits,
+    handler: &dyn Fn(&Request) -> Response,
+) {
+    let mut conn = Connection {
+        stream,
+        buf: buffered,
+        decoder: Decoder::new(),
+        streams: HashMap::new(),
+        last_stream_id: 0,
+        send_window: DEFAULT_WINDOW,
+        recv_window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        continuation: None,
+        going_away: false,
+        limits,
+        handler,
+    };
+
+    let code = match conn.run(upgrade) {
+        Ok(()) => NO_ERROR,
+        Err(H2Error::Connection(code, reason)) => {
+            eprintln!("HTTP/2 connection error: {}", reason);
+            code
+        }
+        Err(H2Error::Io(e)) => match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => NO_ERROR,
+            _ => return,
+        },
+        Err(H2Error::Stream(..)) => unreachable!(),
+    };
+    // the peer may already be gone
+    let _ = conn.go_away(code);
+}
+
+struct Connection<'a, S> {
+    stream: S,
+    buf: Vec<u8>,
+    decoder: Decoder,
+    streams: HashMap<u32, Stream>,
+    last_stream_id: u32,
+    send_window: i64,
+    // DATA the client may still send on the whole connection
+    recv_window: i64,
+    // window new streams start with, set by the client
+    initial_window: i64,
+    // largest frame the client accepts
+    max_frame_size: usize,
+    // header block split over CONTINUATION frames: stream, fragments, END_STREAM
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    going_away: bool,
+    limits: &'a Limits,
+    handler: &'a dyn Fn(&Request) -> Response,
+}
+
+impl<S: Read + Write> Connection<'_, S> {
+    fn run(&mut self, upgrade: Option<Upgrade>) -> Result<(), H2Error> {
+        let mut settings = Vec::new();
+        settings.extend(SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+        settings.extend((MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        settings.extend(SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+        let list_size = self.limits.header_bytes.min(u32::MAX as usize) as u32;
+        settings.extend(list_size.to_be_bytes());
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+        self.stream.flush()?;
+
+        if self.read_bytes(PREFACE.len())? != PREFACE {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "invalid preface"));
+        }
+
+        // the upgraded request is stream 1, already closed by the client
+        if let Some(upgrade) = upgrade {
+            self.apply_settings(&upgrade.settings)?;
+            self.last_stream_id = 1;
+            self.streams.insert(1, self.new_stream(None, true));
+            self.respond(1, &upgrade.request)?;
+        }
+
+        let first = self.read_frame()?;
+        if first.kind != SETTINGS || first.has(ACK) {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "expected SETTINGS"));
+        }
+        self.on_settings(&first)?;
+
+        loop {
+            self.send_pending()?;
+            self.stream.flush()?;
+            if self.going_away && self.streams.is_empty() {
+                return Ok(());
+            }
+
+            let frame = self.read_frame()?;
+            match self.handle(frame) {
+                Err(H2Error::Stream(id, code)) => self.reset(id, code)?,
+                result => result?,
+            }
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), H2Error> {
+        if let Some((id, _, _)) = self.continuation {
+            if frame.kind != CONTINUATION || frame.stream_id != id {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "expected CONTINUATION"));
+            }
+        }
+
+        match frame.kind {
+            DATA => self.on_data(&frame),
+            HEADERS => self.on_headers(&frame),
+            PRIORITY => {
+                if frame.stream_id == 0 {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "PRIORITY on stream 0"));
+                }
+                if frame.payload.len() != 5 {
+                    return Err(H2Error::Stream(frame.stream_id, FRAME_SIZE_ERROR));
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if frame.stream_id == 0 || self.is_idle(frame.stream_id) {
+                    return Err(H2Error::Connection(
+                        PROTOCOL_ERROR,
+                        "RST_STREAM on idle stream",
+                    ));
+                }
+                if frame.payload.len() != 4 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR, "bad RST_STREAM"));
+                }
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            }
+            SETTINGS => self.on_settings(&frame),
+            PUSH_PROMISE => Err(H2Error::Connection(
+                PROTOCOL_ERROR,
+                "PUSH_PROMISE from client",
+            )),
+            PING => {
+                if frame.stream_id != 0 {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "PING on a stream"));
+                }
+                if frame.payload.len() != 8 {
+                    return Err(H2Error::Connection(FRAME_SIZE_ERROR, "bad PING"));
+                }
+                if !frame.has(ACK) {
+                    self.write_frame(PING, ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if frame.stream_id != 0 {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "GOAWAY on a stream"));
+                }
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.on_window_update(&frame),
+            CONTINUATION => self.on_continuation(&frame),
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn on_data(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        // padding counts against the windows too
+        let flow_len = frame.payload.len() as i64;
+        if flow_len > self.recv_window {
+            return Err(H2Error::Connection(
+                FLOW_CONTROL_ERROR,
+                "connection window exceeded",
+            ));
+        }
+        self.recv_window -= flow_len;
+        let result = self.on_stream_data(frame, flow_len);
+        // the data was buffered or dropped either way, so the connection
+        // window is handed back, a stream's own only once its body is taken
+        self.release(0, flow_len)?;
+        result
+    }
+
+    /// Adds DATA to the body of its stream, within the stream's window
+    /// and the body limit
+    fn on_stream_data(&mut self, frame: &Frame, flow_len: i64) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        let data = frame.data()?;
+        if self.is_idle(id) {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "DATA on idle stream"));
+        }
+        let stream = match self.streams.get_mut(&id) {
+            Some(stream) if !stream.recv_closed => stream,
+            _ => return Err(H2Error::Stream(id, STREAM_CLOSED)),
+        };
+        if flow_len > stream.recv_window {
+            return Err(H2Error::Stream(id, FLOW_CONTROL_ERROR));
+        }
+        stream.recv_window -= flow_len;
+        if (stream.body.len() + data.len()) as u64 > self.limits.body {
+            return self.refuse(id, StatusCode::CONTENT_TOO_LARGE);
+        }
+        stream.body.extend_from_slice(data);
+
+        if frame.has(END_STREAM) {
+            stream.recv_closed = true;
+            return self.dispatch(id);
+        }
+        // the window only opens as far as the body limit, and a byte past
+        // it so a client going over is refused rather than left waiting
+        let granted = stream.body.len() as u64 + stream.recv_window as u64;
+        let room = self.limits.body.saturating_add(1).saturating_sub(granted);
+        self.release(id, flow_len.min(room.min(MAX_WINDOW as u64) as i64))
+    }
+
+    /// Hands received bytes back to the client's window for a stream, or
+    /// for the connection with stream 0
+    fn release(&mut self, id: u32, n: i64) -> Result<(), H2Error> {
+        if n == 0 {
+            return Ok(());
+        }
+        match id {
+            0 => self.recv_window += n,
+            _ => match self.streams.get_mut(&id) {
+                Some(stream) => stream.recv_window += n,
+                None => return Ok(()),
+            },
+        }
+        self.write_frame(WINDOW_UPDATE, 0, id, &(n as u32).to_be_bytes())?;
+        Ok(())
+    }
+
+    fn on_headers(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        let id = frame.stream_id;
+        if id == 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "HEADERS on stream 0"));
+        }
+        let mut block = frame.data()?;
+        let mut self_dependent = false;
+        if frame.has(PRIORITY_FLAG) {
+            if block.len() < 5 {
+                return Err(H2Error::Connection(FRAME_SIZE_ERROR, "short HEADERS"));
+            }
+            let dependency = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+            self_dependent = dependency & 0x7fff_ffff == id;
+            block = &block[5..];
+        }
+
+        if !frame.has(END_HEADERS) {
+            self.continuation = Some((id, block.to_vec(), frame.has(END_STREAM)));
+            return Ok(());
+        }
+        let block = block.to_vec();
+        self.on_header_block(id, &block, frame.has(END_STREAM))?;
+        if self_dependent {
+            return Err(H2Error::Stream(id, PROTOCOL_ERROR));
+        }
+        Ok(())
+    }
+
+    fn on_continuation(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        let (id, mut block, end_stream) = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => {
+                return Err(H2Error::Connection(
+                    PROTOCOL_ERROR,
+                    "unexpected CONTINUATION",
+                ))
+            }
+        };
+        block.extend_from_slice(&frame.payload);
+        if block.len() > MAX_HEADER_BLOCK {
+            return Err(H2Error::Connection(
+                ENHANCE_YOUR_CALM,
+                "header block too large",
+            ));
+        }
+
+        if frame.has(END_HEADERS) {
+            return self.on_header_block(id, &block, end_stream);
+        }
+        self.continuation = Some((id, block, end_stream));
+        Ok(())
+    }
+
+    /// Handles a complete header block, opening a stream or ending one with trailers
+    fn on_header_block(&mut self, id: u32, block: &[u8], end_stream: bool) -> Result<(), H2Error> {
+        // the block has to be decoded even if the stream is refused
+        // so the dynamic table stays in sync
+        // a list past the limit is refused once the stream checks pass
+        let fields = match self.decoder.decode(block, self.limits.header_bytes) {
+            Ok(fields) => Some(fields),
+            Err(DecodeError::TooLarge) => None,
+            Err(DecodeError::Invalid(_)) => {
+                return Err(H2Error::Connection(
+                    COMPRESSION_ERROR,
+                    "invalid header block",
+                ))
+            }
+        };
+
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.recv_closed {
+                return Err(H2Error::Stream(id, STREAM_CLOSED));
+            }
+            // trailers have to end the stream
+            if !end_stream {
+                return Err(H2Error::Stream(id, PROTOCOL_ERROR));
+            }
+            stream.recv_closed = true;
+            if fields.is_none() {
+                return self.refuse(id, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            }
+            return self.dispatch(id);
+        }
+
+        if id <= self.last_stream_id {
+            return Err(H2Error::Connection(
+                STREAM_CLOSED,
+                "HEADERS on closed stream",
+            ));
+        }
+        if id.is_multiple_of(2) {
+            return Err(H2Error::Connection(
+                PROTOCOL_ERROR,
+                "even stream id from client",
+            ));
+        }
+        self.last_stream_id = id;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return Err(H2Error::Stream(id, REFUSED_STREAM));
+        }
+
+        let fields = match fields {
+            Some(fields) => fields,
+            None => return self.refuse(id, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+        };
+        if let Some(status) = self.exceeds_limits(&fields) {
+            // later frames of the stream are answered with STREAM_CLOSED
+            return self.refuse(id, status);
+        }
+        let req = build_request(fields).map_err(|_| H2Error::Stream(id, PROTOCOL_ERROR))?;
+        if !end_stream {
+            let length = req.headers().content_length().ok().flatten();
+            if length.is_some_and(|length| length > self.limits.body) {
+                return self.refuse(id, StatusCode::CONTENT_TOO_LARGE);
+            }
+            match req.expects_continue() {
+                Ok(true) => {
+                    let fields = vec![(
+                        ":status".to_string(),
+                        StatusCode::CONTINUE.to_string().into(),
+                    )];
+                    self.write_headers(id, &fields, false)?;
+                }
+                Ok(false) => {}
+                Err(e) => return self.refuse(id, e.status_code()),
+            }
+        }
+        let stream = self.new_stream(Some(req), end_stream);
+        self.streams.insert(id, stream);
+        if end_stream {
+            return self.dispatch(id);
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        if frame.stream_id != 0 {
+            return Err(H2Error::Connection(PROTOCOL_ERROR, "SETTINGS on a stream"));
+        }
+        if frame.has(ACK) {
+            if !frame.payload.is_empty() {
+                return Err(H2Error::Connection(
+                    FRAME_SIZE_ERROR,
+                    "SETTINGS ack with payload",
+                ));
+            }
+            return Ok(());
+        }
+        self.apply_settings(&frame.payload)?;
+        self.write_frame(SETTINGS, ACK, 0, &[])?;
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), H2Error> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(H2Error::Connection(FRAME_SIZE_ERROR, "bad SETTINGS length"));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(H2Error::Connection(PROTOCOL_ERROR, "bad ENABLE_PUSH"));
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = value as i64;
+                    if value > MAX_WINDOW {
+                        return Err(H2Error::Connection(FLOW_CONTROL_ERROR, "window too large"));
+                    }
+                    let delta = value - self.initial_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(H2Error::Connection(FLOW_CONTROL_ERROR, "window overflow"));
+                        }
+                    }
+                    self.initial_window = value;
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let value = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&value) {
+                        return Err(H2Error::Connection(PROTOCOL_ERROR, "bad MAX_FRAME_SIZE"));
+                    }
+                    self.max_frame_size = value;
+                }
+                // the header table size only limits an encoder table, which is not used
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, frame: &Frame) -> Result<(), H2Error> {
+        if frame.payload.len() != 4 {
+            return Err(H2Error::Connection(FRAME_SIZE_ERROR, "bad WINDOW_UPDATE"));
+        }
+        let p = &frame.payload;
+        let increment = (u32::from_be_bytes([p[0], p[1], p[2], p[3]]) & 0x7fff_ffff) as i64;
+        let id = frame.stream_id;
+
+        if id == 0 {
+            if increment == 0 {
+                return Err(H2Error::Connection(PROTOCOL_ERROR, "zero window increment"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(H2Error::Connection(FLOW_CONTROL_ERROR, "window overflow"));
+            }
+            return Ok(());
+        }
+
+        if self.is_idle(id) {
+            return Err(H2Error::Connection(
+                PROTOCOL_ERROR,
+                "WINDOW_UPDATE on idle stream",
+            ));
+        }
+        // updates for streams already closed are ignored
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if increment == 0 {
+                return Err(H2Error::Stream(id, PROTOCOL_ERROR));
+            }
+            stream.send_window += increment;
+            if stream.send_window > MAX_WINDOW {
+                return Err(H2Error::Stream(id, FLOW_CONTROL_ERROR));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands a complete request to the handler
+    fn dispatch(&mut self, id: u32) -> Result<(), H2Error> {
+        let stream = self.streams.get_mut(&id).unwrap();
+        let mut req = match stream.request.take() {
+            Some(req) => req,
+            None => return Err(H2Error::Stream(id, PROTOCOL_ERROR)),
+        };
+        let body = std::mem::take(&mut stream.body);
+
+        // a Content-Length that does not match the DATA frames is malformed
+        match req.headers().content_length() {
+            Ok(Some(length)) if length != body.len() as u64 => {
+                return Err(H2Error::Stream(id, PROTOCOL_ERROR))
+            }
+            Err(_) => return Err(H2Error::Stream(id, PROTOCOL_ERROR)),
+            _ => {}
+        }
+        req.set_body(body);
+        self.respond(id, &req)
+    }
+
+    /// Sends the response headers and queues the body
+    fn respond(&mut self, id: u32, req: &Request) -> Result<(), H2Error> {
+        let mut resp = (self.handler)(req);
+        resp.http_version = HTTPVersion::HTTP2;
+
+        let mut fields = vec![(":status".to_string(), resp.status_code.to_string().into())];
+        fields.extend(
+            resp.header_fields()
+                .into_iter()
+                .filter(|(name, _)| !is_connection_header(name)),
+        );
+        let body = if resp.send_body && !resp.status_code.forbids_body() {
+            resp.body
+        } else {
+            Body::Empty
+        };
+
+        let end_stream = body.is_empty();
+        self.write_headers(id, &fields, end_stream)?;
+        if end_stream {
+            self.streams.remove(&id);
+        } else if let Some(stream) = self.streams.get_mut(&id) {
+            stream.source = Some(body.into_reader());
+        }
+        Ok(())
+    }
+
+    /// Sends queued response bodies as far as the flow control windows allow
+    /// streams take turns so one large body does not hold up the others
+    fn send_pending(&mut self) -> Result<(), H2Error> {
+        loop {
+            let mut ids: Vec<u32> = self
+                .streams
+                .iter()
+                .filter(|(_, s)| s.source.is_some() || !s.pending.is_empty())
+                .map(|(&id, _)| id)
+                .collect();
+            ids.sort_unstable();
+
+            let mut progress = false;
+            for id in ids {
+                let stream = self.streams.get_mut(&id).unwrap();
+                if let Some(source) = stream.source.as_mut() {
+                    match fill_frame(source, &mut stream.pending, self.max_frame_size) {
+                        Ok(true) => stream.source = None,
+                        Ok(false) => {}
+                        // the headers are out, all that is left is to cut the stream short
+                        Err(_) => {
+                            self.reset(id, INTERNAL_ERROR)?;
+                            progress = true;
+                            continue;
+                        }
+                    }
+                }
+
+                let window = stream.send_window.min(self.send_window).max(0) as usize;
+                let n = stream.pending.len().min(self.max_frame_size).min(window);
+                let done = stream.source.is_none() && n == stream.pending.len();
+                if n == 0 && !done {
+                    continue;
+                }
+
+                let chunk: Vec<u8> = stream.pending.drain(..n).collect();
+                stream.send_window -= n as i64;
+                self.send_window -= n as i64;
+                if done {
+                    self.streams.remove(&id);
+                }
+                self.write_frame(DATA, if done { END_STREAM } else { 0 }, id, &chunk)?;
+                progress = true;
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    fn write_headers(
+        &mut self,
+        id: u32,
+        fields: &[(String, Vec<u8>)],
+        end_stream: bool,
+    ) -> Result<(), H2Error> {
+        let block = hpack::encode(fields.iter().map(|(k, v)| (k.as_str(), v)));
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        // an empty block still needs its HEADERS frame
+        let first: &[u8] = fragments.next().unwrap_or(&[]);
+        let mut fragment = first;
+        loop {
+            let last = fragments.peek().is_none();
+            if last {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, id, fragment)?;
+            match fragments.next() {
+                Some(next) => fragment = next,
+                None => return Ok(()),
+            }
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    /// Status a request breaking the limits is refused with, measured as in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE
+    fn exceeds_limits(&self, fields: &[Field]) -> Option<StatusCode> {
+        let path = fields.iter().find(|(name, _)| name == b":path");
+        if path.is_some_and(|(_, value)| value.len() > self.limits.request_line) {
+            return Some(StatusCode::URI_TOO_LONG);
+        }
+        let regular = fields.iter().filter(|(name, _)| !name.starts_with(b":"));
+        let size: usize = fields.iter().map(|(n, v)| n.len() + v.len() + 32).sum();
+        if regular.count() > self.limits.headers || size > self.limits.header_bytes {
+            return Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        }
+        None
+    }
+
+    /// Answers a stream with a bare status and stops reading its request
+    fn refuse(&mut self, id: u32, status: StatusCode) -> Result<(), H2Error> {
+        let fields = vec![(":status".to_string(), status.to_string().into())];
+        self.write_headers(id, &fields, true)?;
+        self.reset(id, NO_ERROR)
+    }
+
+    fn reset(&mut self, id: u32, code: u32) -> Result<(), H2Error> {
+        self.streams.remove(&id);
+        self.write_frame(RST_STREAM, 0, id, &code.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn go_away(&mut self, code: u32) -> io::Result<()> {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend(code.to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)?;
+        self.stream.flush()
+    }
+
+    fn new_stream(&self, request: Option<Request>, recv_closed: bool) -> Stream {
+        Stream {
+            request,
+            body: Vec::new(),
+            recv_closed,
+            send_window: self.initial_window,
+            recv_window: DEFAULT_WINDOW,
+            source: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Checks if the client has not opened the stream yet
+    fn is_idle(&self, id: u32) -> bool {
+        id > self.last_stream_id
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, H2Error> {
+        let header = self.read_bytes(FRAME_HEADER_LEN)?;
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if length > DEFAULT_MAX_FRAME_SIZE {
+            return Err(H2Error::Connection(FRAME_SIZE_ERROR, "frame too large"));
+        }
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        Ok(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: stream_id & 0x7fff_ffff,
+            payload: self.read_bytes(length)?,
+        })
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) -> io::Result<()> {
+        let mut header = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        header.push(kind);
+        header.push(flags);
+        header.extend(id.to_be_bytes());
+        self.stream.write_all(&header)?;
+        self.stream.write_all(payload)
+    }
+
+    /// Reads exactly `n` bytes, using buffered ones first
+    fn read_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut chunk = [0; READ_CHUNK];
+        while self.buf.len() < n {
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+}
+
+fn is_connection_header(name: &str) -> bool {
+    CONNECTION_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Turns the fields of a request header block into a Request
+///
+/// Values are kept as the bytes sent, as with HTTP/1.1, only pseudo-headers
+/// have to be text.
+fn build_request(fields: Vec<Field>) -> Result<Request, Error> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut headers = Headers::new();
+    let mut cookies: Vec<Vec<u8>> = Vec::new();
+
+    for (name, value) in fields {
+        // the same field rules as HTTP/1.1 so a proxy can't be desynced
+        // when the request is passed on (RFC 9113 section 8.2.1)
+        let field_name = name.strip_prefix(b":").unwrap_or(&name);
+        if field_name.is_empty() || !field_name.iter().all(|&b| is_tchar(b as char)) {
+            return Err(Error::BadRequest(format!(
+                "invalid header name {}",
+                String::from_utf8_lossy(&name)
+            )));
+        }
+        // only tchars are left, so the name is ASCII
+        let name = String::from_utf8(name).unwrap_or_default();
+        if name.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err(Error::BadRequest(format!("uppercase header name {}", name)));
+        }
+        let padded = value.first().is_some_and(|b| b" \t".contains(b))
+            || value.last().is_some_and(|b| b" \t".contains(b));
+        if padded || !value.iter().copied().all(is_field_vchar) {
+            return Err(Error::BadRequest(format!("invalid value for {}", name)));
+        }
+        if let Some(pseudo) = name.strip_prefix(':') {
+            let value = String::from_utf8(value)
+                .map_err(|_| Error::BadRequest(format!("invalid value for {}", name)))?;
+            // pseudo-headers come before every regular field
+            if !headers.is_empty() || !cookies.is_empty() {
+                return Err(Error::BadRequest(format!(
+                    "pseudo-header {} after regular fields",
+                    name
+                )));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return Err(Error::BadRequest(format!("unknown pseudo-header {}", name))),
+            };
+            if slot.replace(value).is_some() {
+                return Err(Error::BadRequest(format!(
+                    "repeated pseudo-header {}",
+                    name
+                )));
+            }
+            continue;
+        }
+
+        match name.as_str() {
+            n if is_connection_header(n) => {
+                return Err(Error::BadRequest(format!("connection header {}", n)))
+            }
+            "te" if value != b"trailers" => {
+                return Err(Error::BadRequest("TE other than trailers".to_string()))
+            }
+            // cookies may be split over several fields
+            "cookie" => cookies.push(value),
+            _ => headers.append(&name, &value),
+        }
+    }
+
+    let method = Method::from(
+        method
+            .ok_or_else(|| Error::BadRequest("missing :method".to_string()))?
+            .as_str(),
+    );
+    let target = if method == Method::Connect {
+        if scheme.is_some() || path.is_some() {
+            return Err(Error::BadRequest(
+                "CONNECT with :scheme or :path".to_string(),
+            ));
+        }
+        authority
+            .clone()
+            .ok_or_else(|| Error::BadRequest("CONNECT without :authority".to_string()))?
+    } else {
+        scheme.ok_or_else(|| Error::BadRequest("missing :scheme".to_string()))?;
+        path.filter(|p| !p.is_empty())
+            .ok_or_else(|| Error::BadRequest("missing :path".to_string()))?
+    };
+
+    if let Some(authority) = authority {
+        if !headers.contains("host") {
+            headers.append("host", &authority);
+        }
+    }
+    if !cookies.is_empty() {
+        headers.append("cookie", cookies.join(&b"; "[..]));
+    }
+    Request::from_parts(method, &target, headers, HTTPVersion::HTTP2)
+}
+
+/// Returns the decoded HTTP2-Settings if the request asks to upgrade to h2c
+pub fn upgrade_settings(req: &Request) -> Option<Vec<u8>> {
+    let headers = req.headers();
+    let has = |name: &str, token: &str| {
+        headers
+            .get_list(name)
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(token))
+    };
+    let wants_upgrade = req.http_ver() == HTTPVersion::HTTP11
+        && has("Upgrade", "h2c")
+        && has("Connection", "Upgrade")
+        && has("Connection", "HTTP2-Settings")
+        && req.body().is_empty();
+    if !wants_upgrade {
+        return None;
+    }
+
+    let mut values = headers.get_all("HTTP2-Settings");
+    let value = values.next()?;
+    if values.next().is_some() {
+        return None;
+    }
+    decode_base64url(value.trim()).filter(|s| s.len().is_multiple_of(6))
+}
+
+/// Decodes unpadded base64url (RFC 4648 section 5)
+fn decode_base64url(value: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(value.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in value.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(list: &[(&str, &str)]) -> Vec<Field> {
+        list.iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_build_request() {
+        let req = build_request(fields(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/a%20b?x=1"),
+            ("cookie", "a=1"),
+            ("cookie", "b=2"),
+        ]))
+        .unwrap();
+        assert_eq!(req.method(), Method::Get);
+        assert_eq!(req.target(), "a b");
+        assert_eq!(req.get_param("x"), Some("1"));
+        assert_eq!(req.get_header("Host"), Some("example.com"));
+        assert_eq!(req.get_header("Cookie"), Some("a=1; b=2"));
+        assert_eq!(req.http_ver(), HTTPVersion::HTTP2);
+
+        // values are passed on as sent, like HTTP/1.1 ones
+        let mut binary = fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/")]);
+        binary.push((b"x-latin".to_vec(), b"caf\xe9".to_vec()));
+        let req = build_request(binary.clone()).unwrap();
+        assert_eq!(req.headers().get_bytes("x-latin"), Some(&b"caf\xe9"[..]));
+        binary[2].1 = b"/caf\xe9".to_vec();
+        assert!(build_request(binary).is_err());
+    }
+
+    #[test]
+    fn test_build_request_malformed() {
+        let get = [(":method", "GET"), (":scheme", "http"), (":path", "/")];
+        assert!(build_request(fields(&get)).is_ok());
+        assert!(build_request(fields(&get[..2])).is_err());
+
+        let mut extra = get.to_vec();
+        extra.push(("Accept", "*/*"));
+        assert!(build_request(fields(&extra)).is_err());
+
+        let mut late = vec![("accept", "*/*")];
+        late.extend(get);
+        assert!(build_request(fields(&late)).is_err());
+
+        let mut connection = get.to_vec();
+        connection.push(("connection", "keep-alive"));
+        assert!(build_request(fields(&connection)).is_err());
+
+        let mut repeated = get.to_vec();
+        repeated.push((":path", "/other"));
+        assert!(build_request(fields(&repeated)).is_err());
+
+        for field in [("x-a", "1\r\nx-b: 2"), ("x-a", " padded"), ("x a", "1")] {
+            let mut invalid = get.to_vec();
+            invalid.push(field);
+            assert!(build_request(fields(&invalid)).is_err());
+        }
+
+        let mut lengths = get.to_vec();
+        lengths.push(("content-length", "1, 2"));
+        assert!(build_request(fields(&lengths))
+            .unwrap()
+            .headers()
+            .content_length()
+            .is_err());
+    }
+
+    /// A client connection, scripted input and everything written back
+    struct Duplex {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.extend([kind, flags]);
+        out.extend(id.to_be_bytes());
+        out.extend(payload);
+        out
+    }
+
+    /// Frames as kind, stream and payload
+    fn frames(mut data: &[u8]) -> Vec<(u8, u32, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while data.len() >= FRAME_HEADER_LEN {
+            let len = u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize;
+            let id = u32::from_be_bytes([data[5], data[6], data[7], data[8]]);
+            let end = FRAME_HEADER_LEN + len;
+            frames.push((data[3], id, data[FRAME_HEADER_LEN..end].to_vec()));
+            data = &data[end..];
+        }
+        frames
+    }
+
+    #[test]
+    fn test_receive_windows() {
+        let limits = Limits {
+            body: 100_000,
+            ..Limits::default()
+        };
+        let mut input = PREFACE.to_vec();
+        input.extend(frame(SETTINGS, 0, 0, &[]));
+        let block = hpack::encode([
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", "/"),
+            (":authority", "a"),
+        ]);
+        input.extend(frame(HEADERS, END_HEADERS, 1, &block));
+        for _ in 0..7 {
+            input.extend(frame(DATA, 0, 1, &[b'a'; 16_384]));
+        }
+        let mut conn = Duplex {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        };
+        let handler = |_: &Request| Response::new(HTTPVersion::HTTP2, StatusCode::OK);
+        serve(&mut conn, Vec::new(), None, &limits, &handler);
+
+        let frames = frames(&conn.output);
+        let increments = |id| -> u32 {
+            frames
+                .iter()
+                .filter(|(kind, stream, _)| *kind == WINDOW_UPDATE && *stream == id)
+                .map(|(_, _, p)| u32::from_be_bytes([p[0], p[1], p[2], p[3]]))
+                .sum()
+        };
+        // every frame is handed back to the connection
+        assert_eq!(increments(0), 7 * 16_384);
+        // the stream is only let send a byte past the body limit
+        assert_eq!(increments(1) as i64 + DEFAULT_WINDOW, 100_001);
+        let reset = frames.iter().find(|(kind, _, _)| *kind == RST_STREAM);
+        assert_eq!(
+            reset.map(|(_, id, p)| (*id, p.clone())),
+            Some((1, FLOW_CONTROL_ERROR.to_be_bytes().to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_decode_base64url() {
+        assert_eq!(
+            decode_base64url("AAMAAABkAAQAoAAA"),
+            Some(vec![0, 3, 0, 0, 0, 100, 0, 4, 0, 160, 0, 0])
+        );
+        assert_eq!(decode_base64url("-_8"), Some(vec![0xfb, 0xff]));
+        assert_eq!(decode_base64url("a+b"), None);
+    }
+}
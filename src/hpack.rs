@@ -0,0 +1,828 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Header field as a name and value pair, kept as the octets the peer sent
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// Bytes an entry takes in the dynamic table on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+/// Dynamic table size until the peer changes it with SETTINGS_HEADER_TABLE_SIZE
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// The static table (RFC 7541 appendix A), index 1 is the first entry
+static STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// Huffman code and its length in bits for every symbol (RFC 7541 appendix B)
+/// symbol 256 is EOS
+static HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// Marks a node of the Huffman tree as a leaf holding a symbol
+const LEAF: u16 = 0x8000;
+
+lazy_static! {
+    /// Huffman codes as a binary tree for decoding
+    /// each node holds the next node for a 0 and a 1 bit, or a leaf
+    static ref HUFFMAN_TREE: Vec<[u16; 2]> = {
+        let mut tree = vec![[0u16; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for shift in (0..len).rev() {
+                let bit = ((code >> shift) & 1) as usize;
+                if shift == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0, 0]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    };
+}
+
+/// Failures decoding a header block
+#[derive(Debug)]
+pub enum DecodeError {
+    /// the block can't be decoded, a connection error of type
+    /// COMPRESSION_ERROR (RFC 7541 section 2.3.2)
+    Invalid(String),
+    /// the fields add up past the size allowed, the block was still decoded
+    /// to the end so the dynamic table stays in sync
+    TooLarge,
+}
+
+impl DecodeError {
+    fn new(reason: &str) -> Self {
+        DecodeError::Invalid(reason.to_string())
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Invalid(reason) => write!(f, "invalid header block: {}", reason),
+            DecodeError::TooLarge => f.write_str("header list too large"),
+        }
+    }
+}
+
+/// Decodes Huffman encoded string literals
+fn huffman_decode(input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut decoded = Vec::with_capacity(input.len() * 2);
+    let mut node = 0;
+    // bits read since the last symbol, all of them ones
+    let mut padding = 0;
+    let mut all_ones = true;
+    for byte in input {
+        for shift in (0..8).rev() {
+            let bit = (byte >> shift) & 1;
+            padding += 1;
+            all_ones &= bit == 1;
+            let next = HUFFMAN_TREE[node][bit as usize];
+            if next & LEAF != 0 {
+                let symbol = next & !LEAF;
+                if symbol == 256 {
+                    return Err(DecodeError::new("EOS in huffman string"));
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                padding = 0;
+                all_ones = true;
+            } else {
+                node = next as usize;
+            }
+        }
+    }
+
+    // the string ends with at most seven bits of the EOS prefix
+    if padding > 7 || !all_ones {
+        return Err(DecodeError::new("invalid huffman padding"));
+    }
+    Ok(decoded)
+}
+
+/// Reads an integer with an N-bit prefix (RFC 7541 section 5.1)
+fn decode_int(input: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, rest) = input
+        .split_first()
+        .ok_or_else(|| DecodeError::new("truncated integer"))?;
+    *input = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = (first as usize) & max;
+    if value < max {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| DecodeError::new("truncated integer"))?;
+        *input = rest;
+        if shift > 28 {
+            return Err(DecodeError::new("integer overflow"));
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Writes an integer with an N-bit prefix, `flags` fills the bits above it
+fn encode_int(out: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+/// Reads a string literal, Huffman encoded or not
+fn decode_string(input: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = input.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_int(input, 7)?;
+    if input.len() < len {
+        return Err(DecodeError::new("truncated string"));
+    }
+    let (raw, rest) = input.split_at(len);
+    *input = rest;
+
+    if huffman {
+        huffman_decode(raw)
+    } else {
+        Ok(raw.to_vec())
+    }
+}
+
+/// Writes a string literal without Huffman coding
+fn encode_string(out: &mut Vec<u8>, value: &[u8]) {
+    encode_int(out, 0, 7, value.len());
+    out.extend_from_slice(value);
+}
+
+/// Decodes header blocks, keeping the dynamic table between them
+pub struct Decoder {
+    // newest entry first
+    table: VecDeque<Field>,
+    size: usize,
+    max_size: usize,
+    // limit advertised to the peer, a size update may not go above it
+    limit: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+            limit: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    /// Decodes a complete header block into fields in order
+    ///
+    /// The fields may add up to `max_list_size`, measured as in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE (RFC 7541 section 4.1). Fields past it
+    /// are decoded but never copied out of the table, so a block of small
+    /// references to a large entry can't expand in memory.
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<Field>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut seen_field = false;
+        while let Some(&first) = block.first() {
+            let (name, value, indexing) = if first & 0x80 != 0 {
+                // indexed field
+                let index = decode_int(&mut block, 7)?;
+                let (name, value) = self.get(index)?;
+                (Cow::Borrowed(name), Cow::Borrowed(value), false)
+            } else if first & 0x40 != 0 {
+                // literal with incremental indexing
+                let (name, value) = self.decode_literal(&mut block, 6)?;
+                (name, Cow::Owned(value), true)
+            } else if first & 0x20 != 0 {
+                // size updates are only allowed before the first field
+                if seen_field {
+                    return Err(DecodeError::new("dynamic table size update after a field"));
+                }
+                let size = decode_int(&mut block, 5)?;
+                if size > self.limit {
+                    return Err(DecodeError::new(
+                        "dynamic table size update above the limit",
+                    ));
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // literal without indexing or never indexed
+                let (name, value) = self.decode_literal(&mut block, 4)?;
+                (name, Cow::Owned(value), false)
+            };
+
+            seen_field = true;
+            list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+            let keep = list_size <= max_list_size;
+            if keep || indexing {
+                let field = (name.into_owned(), value.into_owned());
+                if indexing {
+                    self.add(field.clone());
+                }
+                if keep {
+                    fields.push(field);
+                }
+            }
+        }
+        if list_size > max_list_size {
+            return Err(DecodeError::TooLarge);
+        }
+        Ok(fields)
+    }
+
+    /// Reads a literal field, its name borrowed from the tables if indexed
+    fn decode_literal(
+        &self,
+        block: &mut &[u8],
+        prefix: u8,
+    ) -> Result<(Cow<'_, [u8]>, Vec<u8>), DecodeError> {
+        let index = decode_int(block, prefix)?;
+        let name = if index == 0 {
+            Cow::Owned(decode_string(block)?)
+        } else {
+            Cow::Borrowed(self.get(index)?.0)
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    /// Looks up an index across the static and dynamic tables
+    fn get(&self, index: usize) -> Result<(&[u8], &[u8]), DecodeError> {
+        if index == 0 {
+            return Err(DecodeError::new("index 0 is not a field"));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.as_bytes(), value.as_bytes()));
+        }
+        self.table
+            .get(index - STATIC_TABLE.len() - 1)
+            .map(|(name, value)| (name.as_slice(), value.as_slice()))
+            .ok_or_else(|| DecodeError::Invalid(format!("index {} out of the table", index)))
+    }
+
+    /// Adds an entry, sized by its octets as the peer's encoder sizes it
+    fn add(&mut self, field: Field) {
+        self.size += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    /// Drops the oldest entries until the table fits its size
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Encodes a header block
+///
+/// Fields are sent as literals without indexing so no encoder state is kept,
+/// names found in the static table are sent by index and values as the
+/// bytes they hold.
+pub fn encode<'a, V, I>(fields: I) -> Vec<u8>
+where
+    V: AsRef<[u8]>,
+    I: IntoIterator<Item = (&'a str, V)>,
+{
+    let mut out = Vec::new();
+    for (name, value) in fields {
+        let name = name.to_ascii_lowercase();
+        let value = value.as_ref();
+        if let Some(i) = STATIC_TABLE
+            .iter()
+            .position(|&(n, v)| n == name && v.as_bytes() == value)
+        {
+            encode_int(&mut out, 0x80, 7, i + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => encode_int(&mut out, 0, 4, i + 1),
+            None => {
+                out.push(0);
+                encode_string(&mut out, name.as_bytes());
+            }
+        }
+        encode_string(&mut out, value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header list size the tests decode with
+    const LIST: usize = 16384;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<Field> {
+        list.iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut out = Vec::new();
+        encode_int(&mut out, 0, 5, 1337);
+        assert_eq!(out, vec![0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_int(&mut out.as_slice(), 5).unwrap(), 1337);
+        assert!(decode_int(
+            &mut [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff].as_slice(),
+            7
+        )
+        .is_err());
+    }
+
+    /// Requests from RFC 7541 appendix C.4, sharing one dynamic table
+    #[test]
+    fn test_decode_huffman_requests() {
+        let mut decoder = Decoder::new();
+        let first = decoder
+            .decode(&unhex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff"), LIST)
+            .unwrap();
+        assert_eq!(
+            first,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+
+        let second = decoder
+            .decode(&unhex("8286 84be 5886 a8eb 1064 9cbf"), LIST)
+            .unwrap();
+        assert_eq!(
+            second,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+
+        let third = decoder
+            .decode(
+                &unhex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf"),
+                LIST,
+            )
+            .unwrap();
+        assert_eq!(
+            third,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut decoder = Decoder::new();
+        assert!(decoder.decode(&[0x80], LIST).is_err());
+        assert!(decoder.decode(&[0xff, 0x00], LIST).is_err());
+        // size update above the advertised limit
+        assert!(decoder.decode(&unhex("3fe2 1f"), LIST).is_err());
+        assert!(decoder.decode(&unhex("3fe1 1f"), LIST).is_ok());
+        // huffman string padded with a zero bit
+        assert!(decoder.decode(&unhex("0081 fe 00"), LIST).is_err());
+    }
+
+    /// A block of one byte references to a large entry must not expand
+    #[test]
+    fn test_decode_list_size() {
+        let mut decoder = Decoder::new();
+        let mut block = vec![0x40];
+        encode_string(&mut block, b"x-big");
+        encode_string(&mut block, "a".repeat(4000).as_bytes());
+        block.extend(std::iter::repeat_n(0xbe, 60_000));
+        assert!(matches!(
+            decoder.decode(&block, LIST),
+            Err(DecodeError::TooLarge)
+        ));
+
+        // the entry was still added, the table is in sync for the next block
+        let fields = decoder.decode(&[0xbe], LIST).unwrap();
+        assert_eq!(fields[0].0, b"x-big");
+        assert!(matches!(
+            decoder.decode(&[0xbe, 0xbe, 0xbe, 0xbe, 0xbe], LIST),
+            Err(DecodeError::TooLarge)
+        ));
+    }
+
+    /// Octets that aren't UTF-8 are kept and sized as sent, so the table
+    /// evicts the same entries as the peer's
+    #[test]
+    fn test_decode_binary_values() {
+        let mut decoder = Decoder::new();
+        let value = vec![0xff; 2000];
+        let mut block = vec![0x40, 0x03];
+        block.extend(b"x-a");
+        encode_int(&mut block, 0, 7, value.len());
+        block.extend(&value);
+        block.extend([0x40, 0x03]);
+        block.extend(b"x-b");
+        encode_int(&mut block, 0, 7, value.len());
+        block.extend(&value);
+        let decoded = decoder.decode(&block, LIST).unwrap();
+        assert_eq!(decoded[1], (b"x-b".to_vec(), value.clone()));
+        assert_eq!(decoder.size, 2 * (3 + 2000 + ENTRY_OVERHEAD));
+
+        // both entries still fit the peer's 4096 bytes, so both indexes hold
+        let decoded = decoder.decode(&[0xbe, 0xbf], LIST).unwrap();
+        assert_eq!(decoded[0], (b"x-b".to_vec(), value.clone()));
+        assert_eq!(decoded[1], (b"x-a".to_vec(), value));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let list = [
+            (":status", "200"),
+            ("Content-Type", "text/html"),
+            ("x-custom", "value"),
+            (":status", "418"),
+        ];
+        let block = encode(list);
+        let binary = encode([("x-latin", &b"caf\xe9"[..])]);
+        let decoded = Decoder::new().decode(&binary, LIST).unwrap();
+        assert_eq!(decoded[0].1, b"caf\xe9");
+        assert_eq!(block[0], 0x88);
+        let decoded = Decoder::new().decode(&block, LIST).unwrap();
+        assert_eq!(
+            decoded,
+            fields(&[
+                (":status", "200"),
+                ("content-type", "text/html"),
+                ("x-custom", "value"),
+                (":status", "418"),
+            ])
+        );
+    }
+}
@@ -1,52 +1,496 @@
+use crate::error::{Error, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use std::collections::HashMap;
+use std::fmt;
+use std::str::{self, FromStr};
 
-/// HTTP header as tuple of key and value
-pub type Header = (String, String);
+/// HTTP header as tuple of key and value, the value as sent on the wire
+pub type Header = (String, Vec<u8>);
 
-/// HTTP request parameter map of string keys and string values
-pub type Params = HashMap<String, String>;
+/// HTTP request parameter multimap, a key maps to every value sent for it in order
+pub type Params = HashMap<String, Vec<String>>;
 
-/// HTTP headers map of string keys and string values
-pub type Headers = Vec<Header>;
+/// HTTP header fields in the order they were added
+///
+/// Names are matched case-insensitively and a name may appear more than
+/// once, as with `Set-Cookie` or a list split over several lines.
+/// Values are held as bytes and decoded as UTF-8 when read as text.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Headers {
+    fields: Vec<Header>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a field, keeping any earlier ones with the same name
+    pub fn append(&mut self, name: &str, value: impl AsRef<[u8]>) {
+        self.fields
+            .push((name.to_string(), value.as_ref().to_vec()));
+    }
+
+    /// Sets a field, replacing every earlier one with the same name
+    /// the field keeps the position of the first one it replaces
+    pub fn insert(&mut self, name: &str, value: impl AsRef<[u8]>) {
+        match self.position(name) {
+            Some(pos) => {
+                self.fields[pos].1 = value.as_ref().to_vec();
+                let mut i = pos + 1;
+                while i < self.fields.len() {
+                    if self.fields[i].0.eq_ignore_ascii_case(name) {
+                        self.fields.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            None => self.append(name, value),
+        }
+    }
+
+    /// Removes every field with the name, returns whether any was present
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.fields.len();
+        self.fields.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        len != self.fields.len()
+    }
+
+    /// Value of the first field with the name
+    /// None as well if the value is not valid UTF-8
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_bytes(name).and_then(|v| str::from_utf8(v).ok())
+    }
+
+    /// Raw value of the first field with the name
+    pub fn get_bytes(&self, name: &str) -> Option<&[u8]> {
+        self.position(name).map(|pos| self.fields[pos].1.as_slice())
+    }
+
+    /// Values of every field with the name, in order
+    /// values that are not valid UTF-8 are skipped
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .filter_map(|(_, v)| str::from_utf8(v).ok())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    /// Members of a comma separated list, across every field with the name
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.fields
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .filter_map(|(_, v)| str::from_utf8(v).ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    /// Value of the first field with the name parsed as `T`
+    pub fn get_parsed<T: FromStr>(&self, name: &str) -> Option<std::result::Result<T, T::Err>> {
+        self.get(name).map(|v| v.trim().parse())
+    }
+
+    /// Length announced in Content-Length, None if it was not sent
+    /// every value sent has to be the same plain decimal length, anything
+    /// else is framing a proxy could read differently
+    pub fn content_length(&self) -> Result<Option<u64>> {
+        let invalid = || Error::BadRequest("invalid Content-Length".to_string());
+        let mut length = None;
+        for (_, value) in self
+            .fields
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Content-Length"))
+        {
+            let value = str::from_utf8(value).map_err(|_| invalid())?;
+            for part in value.split(',').map(str::trim) {
+                if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                let part: u64 = part.parse().map_err(|_| invalid())?;
+                if length.is_some_and(|length| length != part) {
+                    return Err(Error::BadRequest("conflicting Content-Length".to_string()));
+                }
+                length = Some(part);
+            }
+        }
+        Ok(length)
+    }
+
+    /// Value of the first field with the name parsed as an HTTP-date
+    pub fn get_date(&self, name: &str) -> Option<DateTime<Utc>> {
+        self.get(name).and_then(parse_http_date)
+    }
+
+    /// Fields in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.fields
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+}
+
+impl Extend<Header> for Headers {
+    fn extend<I: IntoIterator<Item = Header>>(&mut self, iter: I) {
+        self.fields.extend(iter)
+    }
+}
+
+impl IntoIterator for Headers {
+    type Item = Header;
+    type IntoIter = std::vec::IntoIter<Header>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.fields.into_iter()
+    }
+}
+
+/// Formats a timestamp as an IMF-fixdate, the preferred HTTP-date format
+pub fn fmt_http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Parses an HTTP-date in any of the three formats recipients must accept
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    [
+        "%a, %d %b %Y %H:%M:%S GMT",
+        "%A, %d-%b-%y %H:%M:%S GMT",
+        "%a %b %e %H:%M:%S %Y",
+    ]
+    .iter()
+    .find_map(|f| NaiveDateTime::parse_from_str(value, f).ok())
+    .map(|date| DateTime::from_utc(date, Utc))
+}
+
+/// Form of a request target (RFC 9112 section 3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetForm {
+    /// a path and query, as in `GET /index.html`
+    Origin,
+    /// a full URI, as sent to proxies: `GET http://example.com/index.html`
+    Absolute,
+    /// the host and port of a tunnel: `CONNECT example.com:443`
+    Authority,
+    /// the server as a whole: `OPTIONS *`
+    Asterisk,
+}
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+/// Request target split into its parts
+///
+/// The path is percent-decoded with its dot segments resolved, the query
+/// is kept apart as the request parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uri {
+    pub form: TargetForm,
+    /// only sent with absolute-form targets
+    pub scheme: Option<String>,
+    /// host and optional port, sent with absolute and authority-form targets
+    pub authority: Option<String>,
+    pub path: String,
+}
+
+/// HTTP request method
+///
+/// Covers the methods defined in RFC 9110 and PATCH from RFC 5789.
+/// Any other valid token is kept as an extension method.
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
 pub enum Method {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Extension(String),
 }
 
-impl ToString for Method {
-    fn to_string(&self) -> String {
+impl Method {
+    /// The method token as sent on the wire
+    pub fn as_str(&self) -> &str {
         match self {
-            Method::Get => "GET".to_string(),
-            Method::Post => "POST".to_string(),
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Extension(m) => m.as_str(),
         }
     }
+
+    /// Checks if the method is one of the standard methods
+    pub fn is_standard(&self) -> bool {
+        !matches!(self, Method::Extension(_))
+    }
+}
+
+impl From<&str> for Method {
+    fn from(value: &str) -> Self {
+        // method tokens are case-sensitive
+        match value {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "CONNECT" => Method::Connect,
+            "OPTIONS" => Method::Options,
+            "TRACE" => Method::Trace,
+            "PATCH" => Method::Patch,
+            m => Method::Extension(m.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum HTTPVersion {
-    HTTP1,
-    HTTP2, // currently not supported
+    HTTP10,
+    HTTP11,
+    HTTP2,
 }
 
-impl ToString for HTTPVersion {
-    fn to_string(&self) -> String {
+impl fmt::Display for HTTPVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            HTTPVersion::HTTP1 => "HTTP/1.1".to_string(),
-            HTTPVersion::HTTP2 => "HTTP/2".to_string(),
+            HTTPVersion::HTTP10 => f.write_str("HTTP/1.0"),
+            HTTPVersion::HTTP11 => f.write_str("HTTP/1.1"),
+            HTTPVersion::HTTP2 => f.write_str("HTTP/2"),
         }
     }
 }
 
-lazy_static! {
-    /// A mapping of status code and their meanings
-    pub static ref STATUS_CODE_MAPPING: HashMap<u16, &'static str> = vec![
-        (200, "OK"),
-        (404, "Not Found"),
-        (405, "Method Not Allowed"),
-        (500, "Internal Server Error")
-    ]
-    .into_iter()
-    .collect();
+/// Response status code (RFC 9110 section 15)
+///
+/// Any three digit code can be sent, the registered ones have a constant
+/// and a canonical reason phrase. Clients treat an unknown code as the
+/// `x00` code of its class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+/// Defines a constant for every registered status and its reason phrase
+macro_rules! status_codes {
+    ($(($code:expr, $name:ident, $reason:expr),)+) => {
+        impl StatusCode {
+            $(pub const $name: StatusCode = StatusCode($code);)+
+
+            /// Reason phrase the IANA registry gives the code
+            pub fn canonical_reason(&self) -> Option<&'static str> {
+                match self.0 {
+                    $($code => Some($reason),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// the IANA HTTP Status Code Registry, leaving out the unused 306 and 418
+status_codes! {
+    (100, CONTINUE, "Continue"),
+    (101, SWITCHING_PROTOCOLS, "Switching Protocols"),
+    (102, PROCESSING, "Processing"),
+    (103, EARLY_HINTS, "Early Hints"),
+    (200, OK, "OK"),
+    (201, CREATED, "Created"),
+    (202, ACCEPTED, "Accepted"),
+    (203, NON_AUTHORITATIVE_INFORMATION, "Non-Authoritative Information"),
+    (204, NO_CONTENT, "No Content"),
+    (205, RESET_CONTENT, "Reset Content"),
+    (206, PARTIAL_CONTENT, "Partial Content"),
+    (207, MULTI_STATUS, "Multi-Status"),
+    (208, ALREADY_REPORTED, "Already Reported"),
+    (226, IM_USED, "IM Used"),
+    (300, MULTIPLE_CHOICES, "Multiple Choices"),
+    (301, MOVED_PERMANENTLY, "Moved Permanently"),
+    (302, FOUND, "Found"),
+    (303, SEE_OTHER, "See Other"),
+    (304, NOT_MODIFIED, "Not Modified"),
+    (305, USE_PROXY, "Use Proxy"),
+    (307, TEMPORARY_REDIRECT, "Temporary Redirect"),
+    (308, PERMANENT_REDIRECT, "Permanent Redirect"),
+    (400, BAD_REQUEST, "Bad Request"),
+    (401, UNAUTHORIZED, "Unauthorized"),
+    (402, PAYMENT_REQUIRED, "Payment Required"),
+    (403, FORBIDDEN, "Forbidden"),
+    (404, NOT_FOUND, "Not Found"),
+    (405, METHOD_NOT_ALLOWED, "Method Not Allowed"),
+    (406, NOT_ACCEPTABLE, "Not Acceptable"),
+    (407, PROXY_AUTHENTICATION_REQUIRED, "Proxy Authentication Required"),
+    (408, REQUEST_TIMEOUT, "Request Timeout"),
+    (409, CONFLICT, "Conflict"),
+    (410, GONE, "Gone"),
+    (411, LENGTH_REQUIRED, "Length Required"),
+    (412, PRECONDITION_FAILED, "Precondition Failed"),
+    (413, CONTENT_TOO_LARGE, "Content Too Large"),
+    (414, URI_TOO_LONG, "URI Too Long"),
+    (415, UNSUPPORTED_MEDIA_TYPE, "Unsupported Media Type"),
+    (416, RANGE_NOT_SATISFIABLE, "Range Not Satisfiable"),
+    (417, EXPECTATION_FAILED, "Expectation Failed"),
+    (421, MISDIRECTED_REQUEST, "Misdirected Request"),
+    (422, UNPROCESSABLE_CONTENT, "Unprocessable Content"),
+    (423, LOCKED, "Locked"),
+    (424, FAILED_DEPENDENCY, "Failed Dependency"),
+    (425, TOO_EARLY, "Too Early"),
+    (426, UPGRADE_REQUIRED, "Upgrade Required"),
+    (428, PRECONDITION_REQUIRED, "Precondition Required"),
+    (429, TOO_MANY_REQUESTS, "Too Many Requests"),
+    (431, REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large"),
+    (451, UNAVAILABLE_FOR_LEGAL_REASONS, "Unavailable For Legal Reasons"),
+    (500, INTERNAL_SERVER_ERROR, "Internal Server Error"),
+    (501, NOT_IMPLEMENTED, "Not Implemented"),
+    (502, BAD_GATEWAY, "Bad Gateway"),
+    (503, SERVICE_UNAVAILABLE, "Service Unavailable"),
+    (504, GATEWAY_TIMEOUT, "Gateway Timeout"),
+    (505, HTTP_VERSION_NOT_SUPPORTED, "HTTP Version Not Supported"),
+    (506, VARIANT_ALSO_NEGOTIATES, "Variant Also Negotiates"),
+    (507, INSUFFICIENT_STORAGE, "Insufficient Storage"),
+    (508, LOOP_DETECTED, "Loop Detected"),
+    (510, NOT_EXTENDED, "Not Extended"),
+    (511, NETWORK_AUTHENTICATION_REQUIRED, "Network Authentication Required"),
+}
+
+impl StatusCode {
+    /// Status with the code, None unless it has three digits
+    pub fn from_u16(code: u16) -> Option<Self> {
+        (100..=999).contains(&code).then_some(Self(code))
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// Reason phrase sent when none is given, empty for unregistered codes
+    pub fn reason(&self) -> &'static str {
+        self.canonical_reason().unwrap_or("")
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(&self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.0)
+    }
+
+    /// Checks if a response with the status never carries content
+    pub fn forbids_body(&self) -> bool {
+        self.is_informational() || *self == Self::NO_CONTENT || *self == Self::NOT_MODIFIED
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers_map() {
+        let mut headers = Headers::new();
+        headers.append("Accept-Encoding", "gzip, br");
+        headers.append("Set-Cookie", "a=1");
+        headers.append("accept-encoding", "zstd");
+        headers.append("Set-Cookie", "b=2");
+        headers.append("Content-Length", " 42 ");
+
+        assert_eq!(headers.get("ACCEPT-ENCODING"), Some("gzip, br"));
+        assert_eq!(
+            headers.get_list("Accept-Encoding"),
+            vec!["gzip", "br", "zstd"]
+        );
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<&str>>(),
+            vec!["a=1", "b=2"]
+        );
+        assert_eq!(headers.content_length().unwrap(), Some(42));
+
+        headers.insert("set-cookie", "c=3");
+        let names: Vec<&str> = headers.iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Accept-Encoding",
+                "Set-Cookie",
+                "accept-encoding",
+                "Content-Length"
+            ]
+        );
+        assert_eq!(headers.get("Set-Cookie"), Some("c=3"));
+
+        assert!(headers.remove("accept-encoding"));
+        assert!(!headers.contains("Accept-Encoding"));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn test_http_date() {
+        let date = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(fmt_http_date(&date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(date)
+        );
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(date));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(StatusCode::IM_USED.as_u16(), 226);
+        assert_eq!(StatusCode::TOO_EARLY.reason(), "Too Early");
+        let unknown = StatusCode::from_u16(299).unwrap();
+        assert_eq!(unknown.reason(), "");
+        assert!(unknown.is_success());
+        assert!(StatusCode::from_u16(99).is_none());
+        assert!(StatusCode::from_u16(1000).is_none());
+        assert!(StatusCode::NOT_MODIFIED.forbids_body());
+    }
 }
@@ -4,26 +4,50 @@
 extern crate lazy_static;
 extern crate tinytemplate;
 
+/// Validators and conditional requests
+mod conditional;
 /// Fimafeng server configuration
 mod config;
+/// Cross-origin resource sharing policies and preflights
+mod cors;
+/// Content codings and their negotiation
+mod encoding;
+/// Errors raised while answering requests and the statuses they map to
+mod error;
 /// File manager handles file serving and templating
 mod file_manager;
+/// Form bodies, url-encoded or multipart
+mod form;
+/// HTTP/2 framing, streams and flow control
+mod h2;
+/// HPACK header compression for HTTP/2
+mod hpack;
 /// http definitions
 mod http;
 /// Simple logger for requests and responses
 mod log;
 /// HTTP request parser in **nom**
 mod parser;
+/// Byte range requests and multipart/byteranges bodies
+mod range;
 /// HTTP Request object
 mod request;
 /// HTTP Response object
 mod response;
+/// Response header rules scoped by path
+mod rules;
+/// Security headers and their presets
+mod security;
 /// Handles incoming connections;
 mod server;
+/// Helpers shared by the unit tests
+#[cfg(test)]
+mod testing;
 
 use crate::config::Config;
 use crate::server::Server;
 
+use std::process;
 use std::sync::{Arc, Barrier};
 use structopt::StructOpt;
 use threadpool::ThreadPool;
@@ -38,29 +62,29 @@ struct Cli {
 fn main() {
     let args = Cli::from_args();
 
-    let cfgs: Vec<Config> = args
+    // every server is set up before any starts, so a bad one stops them all
+    let servers = args
         .configs
         .iter()
-        .map(|cfg_str| Config::try_from(cfg_str.as_str()).unwrap())
-        .collect();
+        .map(|cfg_str| Config::try_from(cfg_str.as_str()).and_then(Server::new))
+        .collect::<Result<Vec<_>, _>>();
+    let servers = match servers {
+        Ok(servers) => servers,
+        Err(e) => {
+            eprintln!("Failed to start: {}", e);
+            process::exit(1);
+        }
+    };
 
     println!("Fimafeng Started");
-    let job_count = cfgs.len();
+    let job_count = servers.len();
     let pool = ThreadPool::new(job_count);
 
     // waits for all threads plus the starter thread
     let barrier = Arc::new(Barrier::new(job_count + 1));
-    for cfg in cfgs {
+    for server in servers {
         let barrier = barrier.clone();
         pool.execute(move || {
-            let server = Server::new(
-                cfg.host.as_str(),
-                cfg.directory.as_str(),
-                cfg.port,
-                cfg.thread_count,
-                cfg.tls,
-            );
-
             server.listen_and_serve();
 
             barrier.wait();
@@ -1,96 +1,272 @@
 use nom::{
-    branch::alt,
-    bytes::complete::{is_not, tag, take_till},
-    character::complete::{alphanumeric0, alphanumeric1},
-    error::VerboseError,
+    bytes::complete::{is_not, tag, take_till, take_while, take_while1},
+    character::complete::{hex_digit1, satisfy},
+    combinator::{map_res, opt, peek, recognize},
+    error::{ErrorKind, ParseError, VerboseError},
     multi::many0,
-    sequence::{pair, preceded, separated_pair, terminated},
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
     IResult,
 };
+use std::str;
 
-use crate::http::{HTTPVersion, Headers, Method, Params};
+use crate::http::{HTTPVersion, Headers, Method, Params, TargetForm};
+
+/// Result of a parser running over raw request bytes
+type ByteResult<'a, T> = IResult<&'a [u8], T, VerboseError<&'a [u8]>>;
+
+/// Characters allowed in a token (RFC 9110 section 5.6.2)
+pub fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// Characters of a URI scheme after its first letter (RFC 3986 section 3.1)
+fn is_scheme_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"+-.".contains(&b)
+}
+
+/// Characters of a URI authority: userinfo, host and port (RFC 3986 section 3.2)
+pub fn is_authority_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~%!$&'()*+,;=:@[]".contains(&b)
+}
+
+/// Bytes allowed in a field value, obs-text included (RFC 9110 section 5.5)
+/// rules out every control character but HTAB, CR and LF among them
+pub fn is_field_vchar(b: u8) -> bool {
+    b == b'\t' || b == b' ' || (b > 0x20 && b != 0x7f)
+}
+
+/// Strips the optional whitespace around a field value
+fn trim_ows(value: &[u8]) -> &[u8] {
+    let value = trim_start(value);
+    let end = value
+        .iter()
+        .rposition(|b| *b != b' ' && *b != b'\t')
+        .map_or(0, |last| last + 1);
+    &value[..end]
+}
+
+/// Skips leading spaces and tabs
+fn trim_start(input: &[u8]) -> &[u8] {
+    let start = input
+        .iter()
+        .position(|b| *b != b' ' && *b != b'\t')
+        .unwrap_or(input.len());
+    &input[start..]
+}
 
 /// parses the HTTP request method
-pub fn parse_method(input: &str) -> IResult<&str, Method, VerboseError<&str>> {
-    let methods: Vec<&str> = vec!["POST", "GET"];
-
-    for method in methods {
-        let res: Result<(&str, &str), nom::Err<VerboseError<&str>>> = tag(method)(input);
-        match res {
-            Ok((i, _)) => {
-                let m = match method {
-                    "POST" => Method::Post,
-                    "GET" => Method::Get,
-                    _ => unreachable!(),
-                };
-                return Ok((i, m));
-            }
-            Err(_) => continue,
-        };
+/// unknown tokens are kept as extension methods
+pub fn parse_method(input: &[u8]) -> ByteResult<'_, Method> {
+    let (i, method) = map_res(take_while1(|b: u8| is_tchar(b as char)), str::from_utf8)(input)?;
+    Ok((i, Method::from(method)))
+}
+
+/// parses the single space separating the parts of the request line
+pub fn parse_sp(input: &[u8]) -> ByteResult<'_, &[u8]> {
+    tag(" ")(input)
+}
+
+/// Request target as sliced from the request line, still percent-encoded
+#[derive(Debug, PartialEq)]
+pub struct RawTarget<'a> {
+    pub form: TargetForm,
+    pub scheme: Option<&'a str>,
+    pub authority: Option<&'a str>,
+    pub path: &'a [u8],
+}
+
+/// parses the request target in any of its four forms (RFC 9112 section 3.2)
+/// CONNECT takes an authority, anything else a path, a full URI or `*`
+/// the query and fragment are left in the remaining input
+pub fn parse_request_target<'a>(input: &'a [u8], method: &Method) -> ByteResult<'a, RawTarget<'a>> {
+    let path = || is_not(&b" \t\r\n?#"[..]);
+    let target = |form, scheme, authority, path| RawTarget {
+        form,
+        scheme,
+        authority,
+        path,
+    };
+
+    if *method == Method::Connect {
+        let (i, authority) = parse_authority(input)?;
+        return Ok((i, target(TargetForm::Authority, None, Some(authority), &[])));
+    }
+    if input.starts_with(b"/") {
+        let (i, p) = path()(input)?;
+        return Ok((i, target(TargetForm::Origin, None, None, p)));
+    }
+    if input.starts_with(b"*") {
+        let (i, p) = tag("*")(input)?;
+        return Ok((i, target(TargetForm::Asterisk, None, None, p)));
     }
 
-    let e = nom::Err::Incomplete(nom::Needed::new(0));
-    Err(e)
+    let scheme = recognize(pair(
+        satisfy(|c| c.is_ascii_alphabetic()),
+        take_while(is_scheme_char),
+    ));
+    let (i, (scheme, authority)) =
+        separated_pair(map_res(scheme, str::from_utf8), tag("://"), parse_authority)(input)?;
+    // an absolute URI may leave out the path, it stands for `/`
+    let (i, p) = opt(preceded(peek(tag("/")), path()))(i)?;
+    let p = p.unwrap_or(b"/");
+    Ok((
+        i,
+        target(TargetForm::Absolute, Some(scheme), Some(authority), p),
+    ))
 }
 
-/// parses the request path
-pub fn parse_request_target(input: &str) -> IResult<&str, &str, VerboseError<&str>> {
-    let input = input.trim_start();
-    is_not(" \t\r\n?")(input)
+/// parses the authority of a URI, its host and optional port
+pub fn parse_authority(input: &[u8]) -> ByteResult<'_, &str> {
+    map_res(take_while1(is_authority_char), str::from_utf8)(input)
 }
 
-/// support HTTP version 1 only
-pub fn parse_http_version(input: &str) -> IResult<&str, HTTPVersion, VerboseError<&str>> {
-    let input = input.trim_start();
-    match terminated(tag("HTTP/1.1"), tag("\r\n"))(input) {
-        Ok((i, _)) => Ok((i, HTTPVersion::HTTP1)),
-        Err(e) => Err(e),
+/// parses the HTTP version at the end of the request line
+/// HTTP/1.x versions above 1.1 are answered as 1.1, a well-formed
+/// version of any other major is a failure so it can be told apart
+/// from a malformed request line
+pub fn parse_http_version(input: &[u8]) -> ByteResult<'_, HTTPVersion> {
+    let digit = || satisfy(|c| c.is_ascii_digit());
+    let (i, (major, minor)) = terminated(
+        preceded(tag("HTTP/"), separated_pair(digit(), tag("."), digit())),
+        tag("\r\n"),
+    )(input)?;
+
+    match (major, minor) {
+        ('1', '0') => Ok((i, HTTPVersion::HTTP10)),
+        ('1', _) => Ok((i, HTTPVersion::HTTP11)),
+        _ => Err(nom::Err::Failure(VerboseError::from_error_kind(
+            input,
+            ErrorKind::Verify,
+        ))),
     }
 }
 
-/// parses the request parameters
+/// parses the query and strips a fragment if one was sent
+/// keys and values are percent-decoded and `+` stands for a space
 /// returns a map empty if no parameters were passed
-pub fn parse_http_params(input: &str) -> IResult<&str, Params, VerboseError<&str>> {
+pub fn parse_http_params(input: &[u8]) -> ByteResult<'_, Params> {
+    let (remaining_input, query) =
+        opt(preceded(tag("?"), take_till(|c| b" \t\r\n#".contains(&c))))(input)?;
+    let (remaining_input, _fragment) =
+        opt(preceded(tag("#"), take_till(|c| b" \t\r\n".contains(&c))))(remaining_input)?;
+
+    match parse_query(query.unwrap_or_default()) {
+        Some(params) => Ok((remaining_input, params)),
+        None => {
+            let e = VerboseError::from_error_kind(input, ErrorKind::Escaped);
+            Err(nom::Err::Failure(e))
+        }
+    }
+}
+
+/// parses `application/x-www-form-urlencoded` pairs, as sent in a query
+/// or a form body
+/// returns None if a key or value does not decode
+pub fn parse_query(query: &[u8]) -> Option<Params> {
     let mut params = Params::new();
-    let res: Result<(&str, Vec<(&str, &str)>), nom::Err<VerboseError<&str>>> = preceded(
-        tag("?"),
-        many0(separated_pair(
-            alphanumeric1,
-            tag("="),
-            alt((terminated(alphanumeric0, tag("&")), alphanumeric0)),
-        )),
-    )(input);
-
-    let (remaining_input, list) = match res {
-        Ok(r) => r,
-        Err(_) => return Ok((input, params)),
+    for pair in query.split(|b| *b == b'&').filter(|p| !p.is_empty()) {
+        let (k, v) = match pair.iter().position(|b| *b == b'=') {
+            Some(eq) => (&pair[..eq], &pair[eq + 1..]),
+            None => (pair, &pair[pair.len()..]),
+        };
+        let k = percent_decode(k, true)?;
+        let v = percent_decode(v, true)?;
+        params.entry(k).or_default().push(v);
+    }
+    Some(params)
+}
+
+/// decodes the %XX escapes of a path or query component
+/// returns None on a malformed escape, an encoded NUL or invalid UTF-8
+pub fn percent_decode(input: &[u8], plus_as_space: bool) -> Option<String> {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'%' => {
+                let hex = input.get(i + 1..i + 3)?;
+                let hex = str::from_utf8(hex).ok()?;
+                if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    if decoded.contains(&0) {
+        return None;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// resolves the `.` and `..` segments of an absolute path (RFC 3986 section 5.2.4)
+/// `..` never climbs above the root
+pub fn remove_dot_segments(path: &str) -> String {
+    let rest = match path.strip_prefix('/') {
+        Some(rest) => rest,
+        None => return path.to_string(),
     };
 
-    for (k, v) in list {
-        params.insert(k.to_string(), v.to_string());
+    let parts: Vec<&str> = rest.split('/').collect();
+    let last = parts.len() - 1;
+    let mut segments: Vec<&str> = Vec::new();
+    for (i, segment) in parts.into_iter().enumerate() {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+        // a trailing dot segment leaves a directory path
+        if i == last && (segment == "." || segment == "..") {
+            segments.push("");
+        }
     }
+    format!("/{}", segments.join("/"))
+}
 
-    Ok((remaining_input, params))
+/// parses one field line (RFC 9112 section 5)
+/// the name is a token directly followed by the colon, and the value
+/// is returned without the whitespace around it
+pub fn parse_field_line(input: &[u8]) -> ByteResult<'_, (&str, &[u8])> {
+    let (i, name) = map_res(take_while1(|b: u8| is_tchar(b as char)), str::from_utf8)(input)?;
+    let (i, value) = delimited(tag(":"), take_while(is_field_vchar), tag("\r\n"))(i)?;
+    Ok((i, (name, trim_ows(value))))
 }
 
 /// parses http headers
-/// goes through lines of headers until can't match any headers
-pub fn parse_http_headers(input: &str) -> IResult<&str, Headers, VerboseError<&str>> {
+/// goes through field lines until one does not match, the caller checks
+/// what is left so a malformed or obs-folded line is not skipped silently
+/// values are kept as bytes and only decoded when read
+pub fn parse_http_headers(input: &[u8]) -> ByteResult<'_, Headers> {
     let mut headers = Headers::new();
+    let (rest_input, fields) = many0(parse_field_line)(input)?;
 
-    let res: Result<(&str, Vec<(&str, &str)>), nom::Err<VerboseError<&str>>> = many0(pair(
-        take_till(|c| c == ':'),
-        preceded(tag(": "), terminated(is_not("\r\n"), tag("\r\n"))),
-    ))(input);
-
-    let (rest_input, res) = res.unwrap();
-
-    for (k, v) in res {
-        headers.push((k.to_string(), v.to_string()));
+    for (k, v) in fields {
+        headers.append(k, v);
     }
     Ok((rest_input, headers))
 }
 
+/// parses the size line of a chunk in a chunked body
+/// chunk extensions are left in the remaining input
+pub fn parse_chunk_size(input: &[u8]) -> ByteResult<'_, usize> {
+    map_res(map_res(hex_digit1, str::from_utf8), |size| {
+        usize::from_str_radix(size, 16)
+    })(input)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,60 +274,210 @@ mod tests {
     use std::collections::HashMap;
     use std::hash::Hash;
 
+    const EMPTY: &[u8] = b"";
+
     #[test]
     fn test_parse_method() {
-        assert_eq!(parse_method("POST"), Ok(("", Method::Post)));
-        assert_eq!(parse_method("GET"), Ok(("", Method::Get)));
+        assert_eq!(parse_method(b"POST"), Ok((EMPTY, Method::Post)));
+        assert_eq!(parse_method(b"GET"), Ok((EMPTY, Method::Get)));
+        assert_eq!(parse_method(b"HEAD /"), Ok((&b" /"[..], Method::Head)));
+        assert_eq!(
+            parse_method(b"OPTIONS *"),
+            Ok((&b" *"[..], Method::Options))
+        );
+        assert_eq!(parse_method(b"PUT"), Ok((EMPTY, Method::Put)));
+        assert_eq!(parse_method(b"DELETE"), Ok((EMPTY, Method::Delete)));
+        assert_eq!(parse_method(b"PATCH"), Ok((EMPTY, Method::Patch)));
+        assert_eq!(parse_method(b"TRACE"), Ok((EMPTY, Method::Trace)));
+        assert_eq!(parse_method(b"CONNECT"), Ok((EMPTY, Method::Connect)));
+        assert_eq!(
+            parse_method(b"PROPFIND /"),
+            Ok((&b" /"[..], Method::Extension("PROPFIND".to_string())))
+        );
+        assert!(parse_method(b" GET").is_err());
+        assert!(parse_method(b"\xffGET").is_err());
     }
 
     #[test]
     fn test_parse_request_target() {
+        let origin = |path| RawTarget {
+            form: TargetForm::Origin,
+            scheme: None,
+            authority: None,
+            path,
+        };
+        let get = &Method::Get;
         assert_eq!(
-            parse_request_target("/accounts/login "),
-            Ok((" ", "/accounts/login"))
-        )
+            parse_request_target(b"/accounts/login ", get),
+            Ok((&b" "[..], origin(b"/accounts/login")))
+        );
+        assert_eq!(
+            parse_request_target(b"/a%20b?x=1 ", get),
+            Ok((&b"?x=1 "[..], origin(b"/a%20b")))
+        );
+        assert_eq!(
+            parse_request_target(b"/page#top ", get),
+            Ok((&b"#top "[..], origin(b"/page")))
+        );
+
+        let (rest, target) = parse_request_target(b"http://example.com:8080/a?b ", get).unwrap();
+        assert_eq!(rest, b"?b ");
+        assert_eq!(target.form, TargetForm::Absolute);
+        assert_eq!(target.scheme, Some("http"));
+        assert_eq!(target.authority, Some("example.com:8080"));
+        assert_eq!(target.path, b"/a");
+        let (rest, target) = parse_request_target(b"https://example.com HTTP/1.1", get).unwrap();
+        assert_eq!(rest, b" HTTP/1.1");
+        assert_eq!(target.path, b"/");
+
+        let (rest, target) = parse_request_target(b"example.com:443 ", &Method::Connect).unwrap();
+        assert_eq!(rest, b" ");
+        assert_eq!(target.form, TargetForm::Authority);
+        assert_eq!(target.authority, Some("example.com:443"));
+
+        let (_, target) = parse_request_target(b"* ", &Method::Options).unwrap();
+        assert_eq!(target.form, TargetForm::Asterisk);
+
+        assert!(parse_request_target(b"index.html ", get).is_err());
+        assert!(parse_request_target(b" /index.html ", get).is_err());
+        assert!(parse_request_target(b"http:/x ", get).is_err());
     }
 
     #[test]
     fn test_parse_http_version() {
         assert_eq!(
-            parse_http_version("HTTP/1.1\r\n"),
-            Ok(("", HTTPVersion::HTTP1))
+            parse_http_version(b"HTTP/1.1\r\n"),
+            Ok((EMPTY, HTTPVersion::HTTP11))
+        );
+        assert_eq!(
+            parse_http_version(b"HTTP/1.0\r\nHost: a\r\n"),
+            Ok((&b"Host: a\r\n"[..], HTTPVersion::HTTP10))
+        );
+        assert!(parse_http_version(b" HTTP/1.0\r\n").is_err());
+        assert_eq!(
+            parse_http_version(b"HTTP/1.2\r\n"),
+            Ok((EMPTY, HTTPVersion::HTTP11))
         );
+        assert!(matches!(
+            parse_http_version(b"HTTP/3.0\r\n"),
+            Err(nom::Err::Failure(_))
+        ));
+        assert!(matches!(
+            parse_http_version(b"HTTP/1.1 \r\n"),
+            Err(nom::Err::Error(_))
+        ));
+        assert!(parse_http_version(b"HTTP/11\r\n").is_err());
     }
 
     #[test]
     fn test_parse_params() {
-        match parse_http_params("?test=hello&base=1") {
+        match parse_http_params(b"?test=hello&base=1") {
             Ok((_, map)) => {
                 let mut params = Params::new();
-                params.insert("test".to_string(), "hello".to_string());
-                params.insert("base".to_string(), "1".to_string());
+                params.insert("test".to_string(), vec!["hello".to_string()]);
+                params.insert("base".to_string(), vec!["1".to_string()]);
                 assert!(keys_match(&map, &params));
             }
             Err(_e) => {}
         };
     }
 
+    #[test]
+    fn test_parse_params_decoding() {
+        let (rest, params) =
+            parse_http_params(b"?q=a%20b+c&tag=x-y&tag=z_1.2&flag&=&e=%C3%A9#top HTTP/1.1")
+                .unwrap();
+        assert_eq!(rest, b" HTTP/1.1");
+        assert_eq!(params["q"], vec!["a b c"]);
+        assert_eq!(params["tag"], vec!["x-y", "z_1.2"]);
+        assert_eq!(params["flag"], vec![""]);
+        assert_eq!(params[""], vec![""]);
+        assert_eq!(params["e"], vec!["é"]);
+
+        let (rest, params) = parse_http_params(b"#frag HTTP/1.1").unwrap();
+        assert_eq!(rest, b" HTTP/1.1");
+        assert!(params.is_empty());
+
+        assert!(parse_http_params(b"?bad=%zz HTTP/1.1").is_err());
+        assert!(parse_http_params(b"?raw=\xff HTTP/1.1").is_err());
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode(b"a%2Fb+c", false), Some("a/b+c".to_string()));
+        assert_eq!(percent_decode(b"a+b", true), Some("a b".to_string()));
+        assert_eq!(percent_decode(b"%4", false), None);
+        assert_eq!(percent_decode(b"%+1", false), None);
+        assert_eq!(percent_decode(b"%00", false), None);
+        assert_eq!(percent_decode(b"%ff", false), None);
+    }
+
+    #[test]
+    fn test_remove_dot_segments() {
+        assert_eq!(remove_dot_segments("/a/b/c/./../../g"), "/a/g");
+        assert_eq!(remove_dot_segments("/a/b/.."), "/a/");
+        assert_eq!(remove_dot_segments("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(remove_dot_segments("/"), "/");
+        assert_eq!(remove_dot_segments("/a/."), "/a/");
+        assert_eq!(remove_dot_segments("*"), "*");
+    }
+
     #[test]
     fn test_parse_headers() {
-        match parse_http_headers("Host: 127.0.0.1\r\nUser-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10.9; rv:50.0) Gecko/20100101 Firefox/50.0\r\n") {
+        match parse_http_headers(b"Host: 127.0.0.1\r\nUser-Agent: Mozilla/5.0 (Macintosh; Intel Mac OS X 10.9; rv:50.0) Gecko/20100101 Firefox/50.0\r\n") {
                 Ok((_, hdrs)) => {
                     let mut headers = Headers::new();
-                    headers.push(("Host".to_string(), "127.0.0.1".to_string()));
-                    headers.push(("User-Agent".to_string(), "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.9; rv:50.0) Gecko/20100101 Firefox/50.0".to_string()));
-                    assert!(compare_vec(&hdrs, &headers));
+                    headers.append("Host", "127.0.0.1");
+                    headers.append("User-Agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.9; rv:50.0) Gecko/20100101 Firefox/50.0");
+                    assert_eq!(hdrs, headers);
+                    assert_eq!(hdrs.get("host"), Some("127.0.0.1"));
                 },
                 Err(_e) => {},
             };
     }
 
-    fn keys_match<T: Eq + Hash, U, V>(map1: &HashMap<T, U>, map2: &HashMap<T, V>) -> bool {
-        map1.len() == map2.len() && map1.keys().all(|k| map2.contains_key(k))
+    #[test]
+    fn test_parse_headers_binary() {
+        let (rest, headers) = parse_http_headers(b"X-Raw: caf\xe9\r\n\r\n").unwrap();
+        assert_eq!(rest, b"\r\n");
+        assert_eq!(headers.get_bytes("x-raw"), Some(&b"caf\xe9"[..]));
+        assert_eq!(headers.get("x-raw"), None);
+    }
+
+    #[test]
+    fn test_parse_field_line() {
+        assert_eq!(
+            parse_field_line(b"Host:example.com \t\r\n"),
+            Ok((EMPTY, ("Host", &b"example.com"[..])))
+        );
+        assert_eq!(
+            parse_field_line(b"X-Empty:\r\n"),
+            Ok((EMPTY, ("X-Empty", EMPTY)))
+        );
+        assert!(parse_field_line(b"Host : example.com\r\n").is_err());
+        assert!(parse_field_line(b" folded\r\n").is_err());
+        assert!(parse_field_line(b"X-A: 1\rX-B: 2\r\n").is_err());
+        assert!(parse_field_line(b"X-A: 1\nX-B: 2\r\n").is_err());
+        assert!(parse_field_line(b"X-A: \x00\r\n").is_err());
+        assert!(parse_field_line(b"X(A): 1\r\n").is_err());
+
+        let (rest, _) = parse_http_headers(b"A: 1\r\n  folded\r\n\r\n").unwrap();
+        assert_eq!(rest, b"  folded\r\n\r\n");
+    }
+
+    #[test]
+    fn test_parse_chunk_size() {
+        assert_eq!(parse_chunk_size(b"1a"), Ok((EMPTY, 26)));
+        assert_eq!(parse_chunk_size(b"0"), Ok((EMPTY, 0)));
+        assert_eq!(
+            parse_chunk_size(b"FF;name=value"),
+            Ok((&b";name=value"[..], 255))
+        );
+        assert!(parse_chunk_size(b"zz").is_err());
+        assert!(parse_chunk_size(b"ffffffffffffffffffff").is_err());
     }
 
-    fn compare_vec<T: PartialEq>(a: &Vec<T>, b: &Vec<T>) -> bool {
-        let matching = a.iter().zip(b.iter()).filter(|&(a, b)| a == b).count();
-        matching == a.len() && matching == b.len()
+    fn keys_match<T: Eq + Hash, U, V>(map1: &HashMap<T, U>, map2: &HashMap<T, V>) -> bool {
+        map1.len() == map2.len() && map1.keys().all(|k| map2.contains_key(k))
     }
 }
@@ -0,0 +1,308 @@
+use chrono::Utc;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Requests asking for more ranges than this get the whole representation
+const MAX_RANGES: usize = 64;
+
+/// A byte range resolved against the length of the representation
+/// both ends are inclusive, as in Content-Range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Value of the Content-Range header sent with the range
+    pub fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, length)
+    }
+}
+
+/// What a Range header asks of a representation
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// no Range header, or one that has to be ignored
+    Full,
+    Satisfiable(Vec<ByteRange>),
+    /// none of the ranges overlap the representation
+    Unsatisfiable,
+}
+
+/// Resolves a Range header (RFC 9110 section 14.2) against a length
+///
+/// Headers with another unit or bad syntax are ignored, as the RFC allows.
+/// Overlapping ranges are merged so a request can't ask for the same
+/// bytes over and over.
+pub fn parse_range(value: &str, length: u64) -> Ranges {
+    let specs = match value.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Full,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return Ranges::Full;
+        }
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Ranges::Full,
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = match (parse_pos(first), parse_pos(last)) {
+            // suffix-range: the last bytes of the representation
+            (None, Some(suffix)) if first.is_empty() => {
+                (suffix > 0 && length > 0).then(|| ByteRange {
+                    start: length.saturating_sub(suffix),
+                    end: length - 1,
+                })
+            }
+            (Some(start), None) if last.is_empty() => (start < length).then(|| ByteRange {
+                start,
+                end: length - 1,
+            }),
+            (Some(start), Some(end)) if start <= end => (start < length).then(|| ByteRange {
+                start,
+                end: end.min(length - 1),
+            }),
+            _ => return Ranges::Full,
+        };
+        ranges.extend(range);
+    }
+
+    if count == 0 {
+        return Ranges::Full;
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    Ranges::Satisfiable(coalesce(ranges))
+}
+
+/// Parses a position made of digits only
+fn parse_pos(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+/// Merges overlapping ranges, keeping the order asked for otherwise
+fn coalesce(ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    let overlaps = ranges.iter().enumerate().any(|(i, a)| {
+        ranges[i + 1..]
+            .iter()
+            .any(|b| a.start <= b.end && b.start <= a.end)
+    });
+    if !overlaps {
+        return ranges;
+    }
+
+    let mut sorted = ranges;
+    sorted.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Body of a multipart/byteranges response
+///
+/// Each range is read from the file as the body is sent, after a part
+/// header naming it.
+pub struct MultipartRanges {
+    file: fs::File,
+    /// part headers and the ranges they introduce
+    parts: Vec<(Vec<u8>, ByteRange)>,
+    closing: Vec<u8>,
+    boundary: String,
+    /// position in the part being read, counting its header first
+    part: usize,
+    offset: u64,
+}
+
+impl MultipartRanges {
+    pub fn new(file: fs::File, ranges: &[ByteRange], content_type: &str, length: u64) -> Self {
+        let boundary = new_boundary();
+        let parts = ranges
+            .iter()
+            .map(|range| {
+                let head = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.content_range(length)
+                );
+                (head.into_bytes(), *range)
+            })
+            .collect();
+        Self {
+            file,
+            parts,
+            closing: format!("\r\n--{}--\r\n", boundary).into_bytes(),
+            boundary,
+            part: 0,
+            offset: 0,
+        }
+    }
+
+    /// Value of the Content-Type header of the response
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Length of the whole body
+    pub fn len(&self) -> u64 {
+        let parts: u64 = self
+            .parts
+            .iter()
+            .map(|(head, range)| head.len() as u64 + range.len())
+            .sum();
+        parts + self.closing.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Read for MultipartRanges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (head, range) = match self.parts.get(self.part) {
+            Some(part) => part,
+            None => {
+                let rest = &self.closing[self.offset as usize..];
+                let n = rest.len().min(buf.len());
+                buf[..n].copy_from_slice(&rest[..n]);
+                self.offset += n as u64;
+                return Ok(n);
+            }
+        };
+
+        let head_len = head.len() as u64;
+        if self.offset < head_len {
+            let rest = &head[self.offset as usize..];
+            let n = rest.len().min(buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            self.offset += n as u64;
+            if self.offset == head_len {
+                self.file.seek(SeekFrom::Start(range.start))?;
+            }
+            return Ok(n);
+        }
+
+        let left = range.len() - (self.offset - head_len);
+        let max = buf.len().min(left.min(usize::MAX as u64) as usize);
+        let n = self.file.read(&mut buf[..max])?;
+        if n == 0 && max > 0 {
+            // the file shrank after its length was announced
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.offset += n as u64;
+        if n as u64 == left {
+            self.part += 1;
+            self.offset = 0;
+        }
+        Ok(n)
+    }
+}
+
+/// Boundary for a multipart body, unique enough not to turn up in the content
+fn new_boundary() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = Utc::now().timestamp_nanos();
+    format!("fimafeng-{:x}-{:x}-{:x}", std::process::id(), nanos, n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        use Ranges::*;
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            Satisfiable(vec![range(0, 499)])
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            Satisfiable(vec![range(500, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-200", 1000),
+            Satisfiable(vec![range(800, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-2000", 1000),
+            Satisfiable(vec![range(0, 999)])
+        );
+        assert_eq!(
+            parse_range("Bytes=900-5000", 1000),
+            Satisfiable(vec![range(900, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-0, 5000-, -1", 1000),
+            Satisfiable(vec![range(0, 0), range(999, 999)])
+        );
+        // overlapping ranges are merged
+        assert_eq!(
+            parse_range("bytes=500-600, 0-10, 550-700, 11-20", 1000),
+            Satisfiable(vec![range(0, 20), range(500, 700)])
+        );
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Unsatisfiable);
+        assert_eq!(parse_range("bytes=5-1", 1000), Full);
+        assert_eq!(parse_range("bytes=a-b", 1000), Full);
+        assert_eq!(parse_range("bytes=+1-2", 1000), Full);
+        assert_eq!(parse_range("items=0-1", 1000), Full);
+        assert_eq!(parse_range("bytes=", 1000), Full);
+    }
+
+    #[test]
+    fn test_multipart_ranges() {
+        let path = TempPath::new("ranges");
+        fs::write(&path, b"0123456789").unwrap();
+        let file = fs::File::open(&path).unwrap();
+
+        let mut body = MultipartRanges::new(file, &[range(0, 1), range(8, 9)], "text/plain", 10);
+        let len = body.len();
+        let boundary = body.boundary.clone();
+        let mut out = Vec::new();
+        body.read_to_end(&mut out).unwrap();
+
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!(len, expected.len() as u64);
+    }
+}
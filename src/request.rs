@@ -1,75 +1,954 @@
-use anyhow::Error;
 use chrono::{NaiveTime, Utc};
+use std::io::{Read, Write};
+use std::str;
+use std::sync::OnceLock;
 
-use crate::http::{HTTPVersion, Header, Headers, Method, Params};
+use crate::error::{Error, Result};
+use crate::form::{self, Form};
+use crate::http::{HTTPVersion, Headers, Method, Params, TargetForm, Uri};
 use crate::parser::{
-    parse_http_headers, parse_http_params, parse_http_version, parse_method, parse_request_target,
+    is_authority_char, is_field_vchar, parse_chunk_size, parse_http_headers, parse_http_params,
+    parse_http_version, parse_method, parse_request_target, parse_sp, percent_decode,
+    remove_dot_segments, RawTarget,
 };
 
+/// Size of each read from the stream
+const READ_CHUNK: usize = 4096;
+
+/// Marks the end of the request line and headers
+const HEAD_TERMINATOR: &[u8] = b"\r\n\r\n";
+
+/// Interim response telling the client to go ahead with its body
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Ends every line of the chunked framing
+const CRLF: &[u8] = b"\r\n";
+
+/// Sizes a request may not exceed, checked as the request is read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// bytes in the request line, 414 past it
+    pub request_line: usize,
+    /// bytes in the header section, 431 past it
+    pub header_bytes: usize,
+    /// number of header fields, 431 past it
+    pub headers: usize,
+    /// bytes in the body, 413 past it
+    pub body: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            request_line: 8 * 1024,
+            header_bytes: 16 * 1024,
+            headers: 100,
+            body: 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks the request head read so far, terminator included once found
+    fn check_head(&self, head: &[u8]) -> Result<()> {
+        let line_end = head.windows(CRLF.len()).position(|w| w == CRLF);
+        if line_end.unwrap_or(head.len()) > self.request_line {
+            return Err(Error::UriTooLong);
+        }
+        let fields = match line_end {
+            Some(end) => &head[end + CRLF.len()..],
+            None => return Ok(()),
+        };
+        // the blank line ending the head is not part of the fields
+        let field_bytes = fields.len().saturating_sub(CRLF.len());
+        let field_count = fields.windows(CRLF.len()).filter(|w| *w == CRLF).count();
+        if field_bytes > self.header_bytes || field_count > self.headers + 1 {
+            return Err(Error::HeadersTooLarge);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     method: Method,
     params: Params,
     headers: Headers,
-    path: String,
+    uri: Uri,
     http_version: HTTPVersion,
-    body: Option<String>,
+    body: Vec<u8>,
+    // fields sent after a chunked body
+    trailers: Headers,
     time: NaiveTime,
+    // form parsed from the body, kept so it is parsed once
+    form: OnceLock<Option<Form>>,
 }
 
-impl TryFrom<&str> for Request {
+impl TryFrom<&[u8]> for Request {
     type Error = Error;
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let (remaining_input, method) = parse_method(value).unwrap();
-        let (remaining_input, path) = parse_request_target(remaining_input).unwrap();
-        let (remaining_input, params) = parse_http_params(remaining_input).unwrap();
-        let (remaining_input, http_version) = parse_http_version(remaining_input).unwrap();
+    fn try_from(value: &[u8]) -> Result<Self> {
+        let (remaining_input, method) = parse_method(value)
+            .map_err(|_| Error::BadRequest("invalid request method".to_string()))?;
+        // the parts of the request line are split by exactly one space
+        let invalid_line = |_| Error::BadRequest("invalid request line".to_string());
+        let (remaining_input, _) = parse_sp(remaining_input).map_err(invalid_line)?;
+        let (remaining_input, target) = parse_request_target(remaining_input, &method)
+            .map_err(|_| Error::BadRequest("invalid request target".to_string()))?;
+        let (remaining_input, params) = parse_http_params(remaining_input)
+            .map_err(|_| Error::BadRequest("invalid query string".to_string()))?;
+        let (remaining_input, _) = parse_sp(remaining_input).map_err(invalid_line)?;
+        let (remaining_input, http_version) = match parse_http_version(remaining_input) {
+            Ok(r) => r,
+            Err(nom::Err::Failure(_)) => return Err(Error::UnsupportedVersion),
+            Err(_) => return Err(Error::BadRequest("invalid HTTP version".to_string())),
+        };
 
-        let (remaining_input, headers) = parse_http_headers(remaining_input).unwrap();
+        let (remaining_input, headers) = parse_http_headers(remaining_input)
+            .map_err(|_| Error::BadRequest("invalid header fields".to_string()))?;
+        // anything before the blank line is a field line that did not parse
+        if remaining_input != CRLF {
+            return Err(Error::BadRequest("invalid header field".to_string()));
+        }
 
-        let mut body: Option<String> = None;
-        if method == Method::Post {
-            body = Some(remaining_input.to_string());
+        Request::new(method, target, params, http_version, headers)
+    }
+}
+
+impl Request {
+    fn new(
+        method: Method,
+        target: RawTarget,
+        params: Params,
+        http_version: HTTPVersion,
+        headers: Headers,
+    ) -> Result<Self> {
+        if target.form == TargetForm::Asterisk && method != Method::Options {
+            return Err(Error::BadRequest(format!("* as the target of {}", method)));
         }
+        let scheme = target.scheme.map(str::to_ascii_lowercase);
+        if scheme.as_ref().is_some_and(|s| s != "http" && s != "https") {
+            return Err(Error::BadRequest(format!(
+                "unsupported scheme: {}",
+                scheme.unwrap_or_default()
+            )));
+        }
+        let path = percent_decode(target.path, false).ok_or_else(|| {
+            Error::BadRequest(format!(
+                "invalid escape in request target: {}",
+                String::from_utf8_lossy(target.path)
+            ))
+        })?;
+        let uri = Uri {
+            form: target.form,
+            scheme,
+            authority: target.authority.map(str::to_string),
+            path: remove_dot_segments(&path),
+        };
+        check_host(&headers, &http_version)?;
 
         Ok(Self {
             method,
-            path: path.to_string(),
+            uri,
             http_version,
             params,
             headers,
-            body,
+            body: Vec::new(),
+            trailers: Headers::new(),
+            form: OnceLock::new(),
             time: Utc::now().time(),
         })
     }
-}
 
-impl Request {
+    /// Builds a request from fields that arrive already split, as with HTTP/2
+    pub fn from_parts(
+        method: Method,
+        target: &str,
+        headers: Headers,
+        http_version: HTTPVersion,
+    ) -> Result<Self> {
+        let (remaining_input, raw_target) = parse_request_target(target.as_bytes(), &method)
+            .map_err(|_| Error::BadRequest("invalid request target".to_string()))?;
+        let (remaining_input, params) = parse_http_params(remaining_input)
+            .map_err(|_| Error::BadRequest("invalid query string".to_string()))?;
+        if !remaining_input.is_empty() {
+            return Err(Error::BadRequest(format!(
+                "invalid request target: {}",
+                target
+            )));
+        }
+        Request::new(method, raw_target, params, http_version, headers)
+    }
+
+    /// An HTTP/1.1 request for `path` with a Host and the given headers
+    #[cfg(test)]
+    pub fn for_test(method: Method, path: &str, headers: &[(&str, &str)]) -> Self {
+        let mut fields = Headers::new();
+        fields.append("Host", "a");
+        for (name, value) in headers {
+            fields.append(name, value);
+        }
+        Request::from_parts(method, path, fields, HTTPVersion::HTTP11).unwrap()
+    }
+
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+        self.form = OnceLock::new();
+    }
+
     pub fn time(&self) -> NaiveTime {
         self.time
     }
 
     pub fn method(&self) -> Method {
-        self.method
+        self.method.clone()
+    }
+
+    /// Value of the first header with the name, matched case-insensitively
+    pub fn get_header(&self, header_name: &str) -> Option<&str> {
+        self.headers.get(header_name)
     }
 
-    pub fn get_header(_header_name: &str) -> Header {
-        todo!()
+    pub fn headers(&self) -> &Headers {
+        &self.headers
     }
 
     pub fn target(&self) -> String {
-        self.path.clone().trim_start_matches('/').to_string()
+        self.uri.path.trim_start_matches('/').to_string()
     }
 
-    pub fn body(&self) -> Option<&String> {
-        self.body.as_ref()
+    /// The request target split into its parts
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Host the request is for, taken from an absolute-form target over
+    /// the Host header as RFC 9112 section 3.2.2 requires
+    pub fn host(&self) -> Option<&str> {
+        match self.uri.form {
+            TargetForm::Absolute => self.uri.authority.as_deref(),
+            _ => self.headers.get("Host"),
+        }
+    }
+
+    /// Body bytes, empty if none was sent
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Fields and files of a form body, None if the body is not a form
+    ///
+    /// Parts are copied out of the body, which is held to the body limit,
+    /// so a form takes at most that much memory again. It is parsed on the
+    /// first call.
+    pub fn form(&self) -> Result<Option<&Form>> {
+        if let Some(form) = self.form.get() {
+            return Ok(form.as_ref());
+        }
+        let form = match self.headers.get("Content-Type") {
+            Some(content_type) => form::parse(content_type, self.body.as_slice())?,
+            None => None,
+        };
+        Ok(self.form.get_or_init(|| form).as_ref())
+    }
+
+    /// Trailer fields sent after a chunked body
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
     }
 
     pub fn http_ver(&self) -> HTTPVersion {
         self.http_version.clone()
     }
 
-    pub fn get_param(_param_name: &str) -> String {
-        todo!()
+    /// First value sent for the query parameter
+    pub fn get_param(&self, param_name: &str) -> Option<&str> {
+        self.params
+            .get(param_name)
+            .and_then(|values| values.first())
+            .map(String::as_str)
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Checks if the client wants the connection kept open after this request
+    /// HTTP/1.1 connections are persistent unless the client asks to close,
+    /// HTTP/1.0 ones only if the client asks to keep them alive
+    pub fn keep_alive(&self) -> bool {
+        let options = self.headers.get_list("Connection");
+        let has = |token: &str| options.iter().any(|t| t.eq_ignore_ascii_case(token));
+        if has("close") {
+            return false;
+        }
+        match self.http_version {
+            HTTPVersion::HTTP10 => has("keep-alive"),
+            _ => true,
+        }
+    }
+
+    /// Checks if the client waits for `100 Continue` before sending its body
+    /// HTTP/1.0 clients can't understand an interim response so their
+    /// expectations are ignored, anything but 100-continue is refused
+    pub fn expects_continue(&self) -> Result<bool> {
+        let expect = match self.headers.get("Expect") {
+            Some(expect) if self.http_version != HTTPVersion::HTTP10 => expect,
+            _ => return Ok(false),
+        };
+        if expect.trim().eq_ignore_ascii_case("100-continue") {
+            Ok(true)
+        } else {
+            Err(Error::ExpectationFailed)
+        }
+    }
+
+    /// Length of the body announced in the Content-Length header
+    fn content_length(&self) -> Result<usize> {
+        let length = self.headers.content_length()?.unwrap_or(0);
+        usize::try_from(length).map_err(|_| Error::PayloadTooLarge)
+    }
+
+    /// Checks if the body is sent with chunked transfer coding
+    /// chunked has to be the final coding applied to a request body, and
+    /// any framing a proxy could read differently is refused (RFC 9112 section 6)
+    fn is_chunked(&self) -> Result<bool> {
+        if !self.headers.contains("Transfer-Encoding") {
+            return Ok(false);
+        }
+        if self.http_version == HTTPVersion::HTTP10 {
+            return Err(Error::BadRequest(
+                "Transfer-Encoding in an HTTP/1.0 request".to_string(),
+            ));
+        }
+        if self.headers.contains("Content-Length") {
+            return Err(Error::BadRequest(
+                "both Transfer-Encoding and Content-Length".to_string(),
+            ));
+        }
+
+        let codings = self.headers.get_list("Transfer-Encoding");
+        let is_chunked = |c: &str| c.eq_ignore_ascii_case("chunked");
+        let chunked_count = codings.iter().filter(|c| is_chunked(c)).count();
+        if !codings.last().is_some_and(|c| is_chunked(c)) || chunked_count > 1 {
+            return Err(Error::BadRequest(format!(
+                "chunked is not the final transfer coding: {}",
+                codings.join(", ")
+            )));
+        }
+        if codings.len() > 1 {
+            return Err(Error::NotImplemented(format!(
+                "unsupported transfer coding: {}",
+                codings.join(", ")
+            )));
+        }
+        Ok(true)
+    }
+}
+
+/// Checks the Host header (RFC 9112 section 3.2)
+/// HTTP/1.1 requests need exactly one and no request may send two,
+/// since a proxy and the server could each pick a different one
+fn check_host(headers: &Headers, http_version: &HTTPVersion) -> Result<()> {
+    let mut hosts = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Host"));
+    match (hosts.next(), hosts.next()) {
+        (None, _) if *http_version == HTTPVersion::HTTP11 => {
+            Err(Error::BadRequest("missing Host header".to_string()))
+        }
+        (None, _) => Ok(()),
+        (Some(_), Some(_)) => Err(Error::BadRequest("duplicate Host header".to_string())),
+        // host and port only, an empty value is allowed for targets without one
+        (Some((_, host)), None) if host.iter().all(|b| is_authority_char(*b) && *b != b'@') => {
+            Ok(())
+        }
+        (Some((_, host)), None) => Err(Error::BadRequest(format!(
+            "invalid Host header: {}",
+            String::from_utf8_lossy(host)
+        ))),
+    }
+}
+
+/// Reads requests off a plain or TLS stream
+///
+/// Bytes read past the end of a request are kept for the next one.
+pub struct RequestReader<S> {
+    stream: S,
+    buf: Vec<u8>,
+    limits: Limits,
+    // body of the request whose head was read last, until it is read
+    pending: Option<PendingBody>,
+}
+
+/// How a body left to read is framed
+#[derive(Debug, Clone, Copy)]
+struct PendingBody {
+    chunked: bool,
+    length: usize,
+    // the client waits for 100 Continue before sending it
+    expects_continue: bool,
+}
+
+impl<S: Read> RequestReader<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+            limits: Limits::default(),
+            pending: None,
+        }
+    }
+
+    /// Applies `limits` to the requests read from now on
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The underlying stream, used to write responses
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Gives back the stream and any bytes read past the last request
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    /// Checks if the stream starts with `prefix`, reading only as far as needed
+    pub fn starts_with(&mut self, prefix: &[u8]) -> Result<bool> {
+        loop {
+            let n = self.buf.len().min(prefix.len());
+            if self.buf[..n] != prefix[..n] {
+                return Ok(false);
+            }
+            if n == prefix.len() {
+                return Ok(true);
+            }
+            match self.fill() {
+                Ok(0) => return Ok(false),
+                Err(Error::Timeout) if self.buf.is_empty() => return Ok(false),
+                result => result?,
+            };
+        }
+    }
+
+    /// Reads until the header terminator and returns the request line and headers
+    fn read_head(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut searched = 0;
+        loop {
+            if let Some(pos) = self.buf[searched..]
+                .windows(HEAD_TERMINATOR.len())
+                .position(|w| w == HEAD_TERMINATOR)
+            {
+                let end = searched + pos + HEAD_TERMINATOR.len();
+                self.limits.check_head(&self.buf[..end])?;
+                return Ok(Some(self.buf.drain(..end).collect()));
+            }
+            self.limits.check_head(&self.buf)?;
+            // the terminator may straddle two reads
+            searched = self.buf.len().saturating_sub(HEAD_TERMINATOR.len() - 1);
+
+            let n = match self.fill() {
+                // an idle connection timing out between requests just ends
+                Err(Error::Timeout) if self.buf.is_empty() => return Ok(None),
+                result => result?,
+            };
+            if n == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(Error::BadRequest(
+                    "connection closed before end of headers".to_string(),
+                ));
+            }
+        }
+    }
+
+    /// Reads exactly `length` body bytes
+    fn read_body(&mut self, length: usize) -> Result<Vec<u8>> {
+        while self.buf.len() < length {
+            if self.fill()? == 0 {
+                return Err(Error::BadRequest(
+                    "connection closed before end of body".to_string(),
+                ));
+            }
+        }
+        Ok(self.buf.drain(..length).collect())
+    }
+
+    /// Decodes a chunked body, collecting any trailer fields
+    fn read_chunked_body(&mut self, trailers: &mut Headers) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let line = self
+                .read_line(self.limits.header_bytes)?
+                .ok_or_else(|| Error::BadRequest("chunk size line too long".to_string()))?;
+            let invalid = || {
+                Error::BadRequest(format!(
+                    "invalid chunk size: {}",
+                    String::from_utf8_lossy(&line)
+                ))
+            };
+            let (ext, size) = parse_chunk_size(&line).map_err(|_| invalid())?;
+            let starts_ext = ext.is_empty() || [b';', b' ', b'\t'].contains(&ext[0]);
+            // a bare CR or LF could end the line early for another parser
+            if !starts_ext || !ext.iter().all(|b| is_field_vchar(*b)) {
+                return Err(invalid());
+            }
+            if size == 0 {
+                break;
+            }
+            // the size is the client's, adding it to the length could overflow
+            if size as u64 > self.limits.body.saturating_sub(body.len() as u64) {
+                return Err(Error::PayloadTooLarge);
+            }
+
+            body.extend(self.read_body(size)?);
+            if self.read_line(0)?.is_some() {
+                continue;
+            }
+            return Err(Error::BadRequest(
+                "chunk data not followed by CRLF".to_string(),
+            ));
+        }
+
+        // trailer section ends with an empty line
+        // and is held to the same limits as the header section
+        let mut trailer_bytes = 0;
+        loop {
+            let line = self
+                .read_line(self.limits.header_bytes - trailer_bytes)?
+                .ok_or(Error::HeadersTooLarge)?;
+            if line.is_empty() {
+                return Ok(body);
+            }
+            trailer_bytes += line.len() + CRLF.len();
+            if trailer_bytes > self.limits.header_bytes || trailers.len() >= self.limits.headers {
+                return Err(Error::HeadersTooLarge);
+            }
+            let invalid = || {
+                Error::BadRequest(format!(
+                    "invalid trailer field: {}",
+                    String::from_utf8_lossy(&line)
+                ))
+            };
+            let field = [line.as_slice(), CRLF].concat();
+            let (rest, fields) = parse_http_headers(&field).map_err(|_| invalid())?;
+            if !rest.is_empty() {
+                return Err(invalid());
+            }
+            // trailers can't change how the message was framed
+            if fields.contains("Content-Length") || fields.contains("Transfer-Encoding") {
+                return Err(invalid());
+            }
+            trailers.extend(fields);
+        }
+    }
+
+    /// Reads a line and returns it without the CRLF
+    /// returns None once the line is known to be longer than `max`
+    fn read_line(&mut self, max: usize) -> Result<Option<Vec<u8>>> {
+        let mut searched = 0;
+        loop {
+            if let Some(pos) = self.buf[searched..]
+                .windows(CRLF.len())
+                .position(|w| w == CRLF)
+            {
+                let end = searched + pos;
+                if end > max {
+                    return Ok(None);
+                }
+                let line = self.buf.drain(..end).collect();
+                self.buf.drain(..CRLF.len());
+                return Ok(Some(line));
+            }
+            if self.buf.len() > max + CRLF.len() {
+                return Ok(None);
+            }
+            searched = self.buf.len().saturating_sub(CRLF.len() - 1);
+
+            if self.fill()? == 0 {
+                return Err(Error::BadRequest(
+                    "connection closed inside chunked body".to_string(),
+                ));
+            }
+        }
+    }
+
+    /// Appends the next read from the stream to the buffer
+    fn fill(&mut self) -> Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+        let n = self.stream.read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> RequestReader<S> {
+    /// Reads the next request
+    /// returns None if the peer closed the stream before sending anything
+    pub fn read_request(&mut self) -> Result<Option<Request>> {
+        let mut req = match self.read_request_head()? {
+            Some(req) => req,
+            None => return Ok(None),
+        };
+        self.read_request_body(&mut req)?;
+        Ok(Some(req))
+    }
+
+    /// Reads the next request line and headers, leaving the body to
+    /// `read_request_body` so the request can be refused before it is sent
+    pub fn read_request_head(&mut self) -> Result<Option<Request>> {
+        let head = match self.read_head()? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let req = Request::try_from(head.as_slice())?;
+
+        let chunked = req.is_chunked()?;
+        let length = if chunked { 0 } else { req.content_length()? };
+        // refuse before reading so the body is never buffered
+        if length as u64 > self.limits.body {
+            return Err(Error::PayloadTooLarge);
+        }
+        let expects_continue = req.expects_continue()?;
+        self.pending = (chunked || length > 0).then_some(PendingBody {
+            chunked,
+            length,
+            expects_continue,
+        });
+        Ok(Some(req))
+    }
+
+    /// Checks if the client is waiting for `100 Continue` before it sends
+    /// the body of the request just read
+    pub fn awaits_continue(&self) -> bool {
+        // no need to ask for a body the client already started sending
+        self.pending.is_some_and(|p| p.expects_continue) && self.buf.is_empty()
+    }
+
+    /// Reads the body of the request whose head was read last
+    /// `100 Continue` is only sent now, once the body is wanted
+    pub fn read_request_body(&mut self, req: &mut Request) -> Result<()> {
+        if self.awaits_continue() {
+            self.stream.write_all(CONTINUE)?;
+            self.stream.flush()?;
+        }
+        match self.pending.take() {
+            Some(body) if body.chunked => {
+                req.body = self.read_chunked_body(&mut req.trailers)?;
+            }
+            Some(body) => req.body = self.read_body(body.length)?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Trickle;
+
+    #[test]
+    fn test_read_request_across_reads() {
+        let cookie = "a".repeat(2000);
+        let raw = format!(
+            "POST /upload HTTP/1.1\r\nHost: a\r\nCookie: {}\r\nContent-Length: 11\r\n\r\nhello world",
+            cookie
+        );
+        let mut reader = RequestReader::new(Trickle {
+            data: raw.as_bytes(),
+            step: 7,
+        });
+
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.method(), Method::Post);
+        assert_eq!(req.target(), "upload");
+        assert_eq!(req.body(), b"hello world");
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_chunked_request() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nChecksum: abc\r\n\r\n\
+                   GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut reader = RequestReader::new(Trickle {
+            data: raw.as_bytes(),
+            step: 3,
+        });
+
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.body(), b"hello world");
+        assert_eq!(req.trailers().get("checksum"), Some("abc"));
+        let next = reader.read_request().unwrap().unwrap();
+        assert_eq!(next.method(), Method::Get);
+    }
+
+    #[test]
+    fn test_read_request_binary() {
+        let raw = b"POST /bin HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\nContent-Length: 3\r\n\r\n\x00\xff\x80";
+        let mut reader = RequestReader::new(Trickle { data: raw, step: 4 });
+
+        let req = reader.read_request().unwrap().unwrap();
+        assert_eq!(req.body(), b"\x00\xff\x80");
+        assert_eq!(req.headers().get_bytes("x-name"), Some(&b"caf\xe9"[..]));
+    }
+
+    #[test]
+    fn test_read_request_ambiguous_framing() {
+        let read = |raw: &'static str| {
+            RequestReader::new(Trickle {
+                data: raw.as_bytes(),
+                step: 512,
+            })
+            .read_request()
+        };
+        let bad_request = |raw| matches!(read(raw), Err(Error::BadRequest(_)));
+
+        assert!(read("POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3, 3\r\n\r\nabc").is_ok());
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +3\r\n\r\nabc"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, chunked\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3;a\nb\r\nabc\r\n0\r\n\r\n"
+        ));
+        assert!(bad_request(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nContent-Length: 5\r\n\r\n"
+        ));
+        assert!(bad_request(
+            "GET / HTTP/1.1\r\nHost: a\r\nHost: a\r\n folded\r\n\r\n"
+        ));
+        assert!(matches!(
+            read("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n"),
+            Err(Error::NotImplemented(_))
+        ));
+    }
+
+    #[test]
+    fn test_request_form() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\n\
+                   Content-Type: application/x-www-form-urlencoded\r\n\r\n";
+        let mut req = Request::try_from(raw.as_bytes()).unwrap();
+        req.set_body(b"a=1".to_vec());
+        let form = req.form().unwrap().unwrap();
+        assert_eq!(form.get("a"), Some("1"));
+        assert!(std::ptr::eq(form, req.form().unwrap().unwrap()));
+
+        req.set_body(b"a=2".to_vec());
+        assert_eq!(req.form().unwrap().unwrap().get("a"), Some("2"));
+    }
+
+    #[test]
+    fn test_request_line() {
+        let parse = |raw: &str| Request::try_from(raw.as_bytes());
+        assert!(parse("GET / HTTP/1.1\r\nHost: a\r\n\r\n").is_ok());
+        for raw in [
+            "GET  / HTTP/1.1\r\nHost: a\r\n\r\n",
+            "GET\t/ HTTP/1.1\r\nHost: a\r\n\r\n",
+            "GET /  HTTP/1.1\r\nHost: a\r\n\r\n",
+            "GET /\tHTTP/1.1\r\nHost: a\r\n\r\n",
+            "GET /HTTP/1.1\r\nHost: a\r\n\r\n",
+        ] {
+            assert!(matches!(parse(raw), Err(Error::BadRequest(_))), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_request_host() {
+        let parse = |raw: &str| Request::try_from(raw.as_bytes());
+        let bad_request = |raw| matches!(parse(raw), Err(Error::BadRequest(_)));
+
+        assert!(bad_request("GET / HTTP/1.1\r\n\r\n"));
+        assert!(bad_request("GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"));
+        assert!(bad_request("GET / HTTP/1.1\r\nHost: a b\r\n\r\n"));
+        assert!(bad_request("GET / HTTP/1.1\r\nHost: user@a\r\n\r\n"));
+        assert!(bad_request("GET * HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(bad_request("GET ftp://a/ HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").is_ok());
+        assert!(parse("OPTIONS * HTTP/1.1\r\nHost: a\r\n\r\n").is_ok());
+
+        let req = parse("GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").unwrap();
+        assert_eq!(req.uri().form, TargetForm::Origin);
+        assert_eq!(req.host(), Some("example.com:8080"));
+
+        let req =
+            parse("GET HTTP://Example.com/a/../b?q=1 HTTP/1.1\r\nHost: other\r\n\r\n").unwrap();
+        assert_eq!(req.uri().scheme.as_deref(), Some("http"));
+        assert_eq!(req.host(), Some("Example.com"));
+        assert_eq!(req.target(), "b");
+        assert_eq!(req.get_param("q"), Some("1"));
+
+        let req =
+            parse("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+        assert_eq!(req.uri().form, TargetForm::Authority);
+        assert_eq!(req.uri().authority.as_deref(), Some("example.com:443"));
+    }
+
+    #[test]
+    fn test_read_request_truncated() {
+        let mut reader = RequestReader::new(Trickle {
+            data: b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nshort",
+            step: 512,
+        });
+        assert!(matches!(reader.read_request(), Err(Error::BadRequest(_))));
+    }
+
+    #[test]
+    fn test_read_request_limits() {
+        let limits = Limits {
+            request_line: 32,
+            header_bytes: 64,
+            headers: 3,
+            body: 8,
+        };
+        let read = |raw: &'static str| {
+            RequestReader::new(Trickle {
+                data: raw.as_bytes(),
+                step: 5,
+            })
+            .with_limits(limits)
+            .read_request()
+        };
+
+        assert!(read("GET /ok HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+        let long_target = "GET /aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        assert!(matches!(read(long_target), Err(Error::UriTooLong)));
+        let many = "GET / HTTP/1.1\r\nHost: a\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(read(many), Err(Error::HeadersTooLarge)));
+        let large = "GET / HTTP/1.1\r\nHost: a\r\nCookie: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        assert!(matches!(read(large), Err(Error::HeadersTooLarge)));
+        let body = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 9\r\n\r\n";
+        assert!(matches!(read(body), Err(Error::PayloadTooLarge)));
+        let chunked =
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\n";
+        assert!(matches!(read(chunked), Err(Error::PayloadTooLarge)));
+        let huge = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                    5\r\nhello\r\nffffffffffffffff\r\n";
+        assert!(matches!(read(huge), Err(Error::PayloadTooLarge)));
+    }
+
+    /// Returns its data then times out like an idle socket
+    struct Stalled<'a>(&'a [u8]);
+
+    impl Read for Stalled<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    impl Write for Stalled<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Holds its body back until told to continue
+    struct Uploader<'a> {
+        head: &'a [u8],
+        body: &'a [u8],
+        sent: Vec<u8>,
+    }
+
+    impl Read for Uploader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let data = if !self.head.is_empty() {
+                &mut self.head
+            } else if self.sent == CONTINUE {
+                &mut self.body
+            } else {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            };
+            let n = buf.len().min(data.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            *data = &data[n..];
+            Ok(n)
+        }
+    }
+
+    impl Write for Uploader<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_request_expect_continue() {
+        let mut reader = RequestReader::new(Uploader {
+            head:
+                b"PUT /f HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            body: b"hello",
+            sent: Vec::new(),
+        });
+        let mut req = reader.read_request_head().unwrap().unwrap();
+        assert!(reader.awaits_continue());
+        assert!(reader.get_mut().sent.is_empty());
+        reader.read_request_body(&mut req).unwrap();
+        assert_eq!(req.body(), b"hello");
+        assert_eq!(reader.get_mut().sent, CONTINUE);
+
+        let mut reader = RequestReader::new(Uploader {
+            head:
+                b"PUT /f HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            body: b"hello",
+            sent: Vec::new(),
+        })
+        .with_limits(Limits {
+            body: 4,
+            ..Limits::default()
+        });
+        assert!(matches!(reader.read_request(), Err(Error::PayloadTooLarge)));
+        assert!(reader.get_mut().sent.is_empty());
+
+        let mut reader = RequestReader::new(Uploader {
+            head: b"PUT /f HTTP/1.1\r\nHost: a\r\nExpect: something\r\nContent-Length: 5\r\n\r\n",
+            body: b"hello",
+            sent: Vec::new(),
+        });
+        assert!(matches!(
+            reader.read_request(),
+            Err(Error::ExpectationFailed)
+        ));
+    }
+
+    #[test]
+    fn test_read_request_timeout() {
+        let mut reader = RequestReader::new(Stalled(b""));
+        assert!(reader.read_request().unwrap().is_none());
+
+        let mut reader = RequestReader::new(Stalled(b"GET / HTTP/1.1\r\nHost: a\r\nHost"));
+        assert!(matches!(reader.read_request(), Err(Error::Timeout)));
     }
 }
@@ -1,58 +1,478 @@
-use crate::http::{HTTPVersion, STATUS_CODE_MAPPING};
+use crate::http::{fmt_http_date, HTTPVersion, Headers, StatusCode};
 use chrono::{DateTime, Utc};
+use rustls::{ServerConnection, StreamOwned};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// Content sent after the response headers
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// read to its end while the response is written
+    /// with the length if it is known up front
+    Stream {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    /// the next `length` bytes of an open file, sent without copying where
+    /// the connection allows it
+    File {
+        file: fs::File,
+        length: u64,
+    },
+}
+
+impl Body {
+    /// Length of the content, None if it is only known once streamed
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Stream { length, .. } => *length,
+            Body::File { length, .. } => Some(*length),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Reader over the content, for connections that send it piece by piece
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        match self {
+            Body::Empty => Box::new(io::empty()),
+            Body::Bytes(bytes) => Box::new(io::Cursor::new(bytes)),
+            Body::Stream { reader, .. } => reader,
+            Body::File { file, length } => Box::new(file.take(length)),
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::Stream { length, .. } => write!(f, "Stream({:?})", length),
+            Body::File { length, .. } => write!(f, "File({} bytes)", length),
+        }
+    }
+}
 
 /// HTTP response object
+///
+/// Built with `new` and the `with_` methods, then written straight to a
+/// stream with `write_to`. Content-Length, Transfer-Encoding, Connection
+/// and Date are set from the response itself when it is written.
+#[derive(Debug)]
 pub struct Response {
     pub http_version: HTTPVersion,
-    pub status_code: u16,
-    pub content: String,
-    pub content_type: String,
-    pub content_length: u64,
-    pub server_name: String,
+    pub status_code: StatusCode,
+    /// Reason phrase sent instead of the canonical one
+    pub reason: Option<String>,
     pub date: DateTime<Utc>,
+    pub headers: Headers,
+    pub body: Body,
+    /// HEAD responses carry the headers of the representation but no body
+    pub send_body: bool,
+    /// Connection stays open for further requests after this response
+    pub keep_alive: bool,
 }
 
 impl Response {
-    pub fn new(
-        http_version: HTTPVersion,
-        status_code: u16,
-        content: String,
-        content_type: String,
-        content_length: u64,
-        server_name: String,
-    ) -> Self {
+    pub fn new(http_version: HTTPVersion, status_code: StatusCode) -> Self {
         Self {
             http_version,
             status_code,
-            content,
-            content_type,
-            content_length,
-            server_name,
+            reason: None,
             date: Utc::now(),
+            headers: Headers::new(),
+            body: Body::Empty,
+            send_body: true,
+            keep_alive: true,
+        }
+    }
+
+    /// Appends a header, keeping any earlier ones with the same name
+    pub fn with_header(mut self, name: &str, value: impl AsRef<[u8]>) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sends a reason phrase of its own, dropping characters a status line can't carry
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        let reason = reason
+            .chars()
+            .filter(|&c| c == '\t' || !c.is_control())
+            .collect();
+        self.reason = Some(reason);
+        self
+    }
+
+    pub fn with_content_type(self, content_type: &str) -> Self {
+        self.with_header("Content-Type", content_type)
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Streams the body from a reader, sent chunked if the length is not known
+    pub fn with_stream(mut self, reader: impl Read + Send + 'static, length: Option<u64>) -> Self {
+        self.body = Body::Stream {
+            reader: Box::new(reader),
+            length,
+        };
+        self
+    }
+
+    /// Sends `length` bytes of an open file from its current position
+    pub fn with_file(mut self, file: fs::File, length: u64) -> Self {
+        self.body = Body::File { file, length };
+        self
+    }
+
+    /// Appends a header to the response
+    pub fn add_header(&mut self, name: &str, value: impl AsRef<[u8]>) {
+        self.headers.append(name, value);
+    }
+
+    /// Sets a header, replacing any earlier value
+    pub fn set_header(&mut self, name: &str, value: impl AsRef<[u8]>) {
+        self.headers.insert(name, value);
+    }
+
+    /// Value of a header set on the response
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Drops the body while keeping the representation headers
+    pub fn strip_body(&mut self) {
+        self.send_body = false;
+    }
+
+    /// Only HTTP/1.1 has chunked coding, other versions get a Content-Length
+    /// a body of unknown length is always chunked where it can be
+    fn is_chunked(&self) -> bool {
+        self.http_version == HTTPVersion::HTTP11 && self.body.len().is_none()
+    }
+
+    /// Checks if the connection can stay open after the response
+    /// an HTTP/1.0 body of unknown length is only delimited by closing
+    pub fn keeps_alive(&self) -> bool {
+        self.keep_alive && (self.is_chunked() || self.body.len().is_some())
+    }
+
+    /// Writes the response to the stream, streaming the body as it goes
+    pub fn write_to<W: Sink>(self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.head())?;
+        if !self.send_body || self.status_code.forbids_body() {
+            return Ok(());
+        }
+
+        let chunked = self.is_chunked();
+        match self.body {
+            Body::File { mut file, length } if !chunked => w.send_file(&mut file, length),
+            Body::Bytes(bytes) if !chunked => w.write_all(&bytes),
+            body if !chunked => io::copy(&mut body.into_reader(), w).map(|_| ()),
+            body => {
+                let mut chunks = ChunkedWriter::new(w);
+                io::copy(&mut body.into_reader(), &mut chunks)?;
+                chunks.finish(&Headers::new()).map(|_| ())
+            }
+        }
+    }
+
+    /// Status line and headers, up to and including the empty line
+    fn head(&self) -> Vec<u8> {
+        let reason = self
+            .reason
+            .as_deref()
+            .unwrap_or_else(|| self.status_code.reason());
+        let mut head =
+            format!("{} {} {}\r\n", self.http_version, self.status_code, reason).into_bytes();
+        for (name, value) in self.framed_headers().iter() {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// Headers set on the response along with the ones describing its framing
+    /// the framing ones are always computed, the others only when not set
+    fn framed_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        if !self.headers.contains("Date") {
+            headers.append("Date", fmt_http_date(&self.date));
+        }
+        headers.extend(
+            self.headers
+                .clone()
+                .into_iter()
+                .filter(|(name, _)| !is_framing_header(name)),
+        );
+        if self.status_code.forbids_body() {
+            // these never have content to frame, a 304 describes the
+            // representation through its validators instead
+        } else if self.is_chunked() {
+            headers.append("Transfer-Encoding", "chunked");
+        } else if let Some(length) = self.body.len() {
+            headers.append("Content-Length", length.to_string());
         }
+        let connection = if self.keeps_alive() {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        headers.append("Connection", connection);
+        headers
+    }
+
+    /// Every header sent with the response, for HTTP/2
+    pub fn header_fields(&self) -> Vec<(String, Vec<u8>)> {
+        self.framed_headers().into_iter().collect()
+    }
+}
+
+/// Headers the response sets itself from its body and connection state
+fn is_framing_header(name: &str) -> bool {
+    ["Content-Length", "Transfer-Encoding", "Connection"]
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Connection a response is written to
+///
+/// Files are copied through a buffer by default. Plain TCP on Linux hands
+/// them to the kernel with sendfile instead, so their content never passes
+/// through the server.
+pub trait Sink: Write {
+    /// Sends `length` bytes of the file from its current position
+    fn send_file(&mut self, file: &mut fs::File, length: u64) -> io::Result<()> {
+        copy_file(self, file, length)
+    }
+}
+
+impl Sink for Vec<u8> {}
+
+impl Sink for StreamOwned<ServerConnection, TcpStream> {}
+
+#[cfg(not(target_os = "linux"))]
+impl Sink for TcpStream {}
+
+#[cfg(target_os = "linux")]
+impl Sink for TcpStream {
+    fn send_file(&mut self, file: &mut fs::File, length: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut remaining = length;
+        while remaining > 0 {
+            // Linux sends at most 0x7ffff000 bytes per call
+            let count = remaining.min(0x7fff_f000) as usize;
+            // a null offset reads from and advances the file position
+            let sent = unsafe {
+                libc::sendfile(
+                    self.as_raw_fd(),
+                    file.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    count,
+                )
+            };
+            if sent > 0 {
+                remaining -= sent as u64;
+                continue;
+            }
+            if sent == 0 {
+                // the file shrank after its length was announced
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // files sendfile can't read from are copied instead
+                Some(libc::EINVAL) | Some(libc::ENOSYS) if remaining == length => {
+                    return copy_file(self, file, length)
+                }
+                _ => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copies part of a file through a buffer
+fn copy_file<W: Write + ?Sized>(w: &mut W, file: &mut fs::File, length: u64) -> io::Result<()> {
+    let copied = io::copy(&mut file.take(length), w)?;
+    if copied < length {
+        // the file shrank after its length was announced
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Encodes everything written to it with chunked transfer coding
+///
+/// Each write becomes one chunk. `finish` has to be called to send the
+/// last chunk and the trailer fields.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Writes the last chunk and trailers, and returns the inner writer
+    pub fn finish(mut self, trailers: &Headers) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n")?;
+        for (name, value) in trailers.iter() {
+            write!(self.inner, "{}: ", name)?;
+            self.inner.write_all(value)?;
+            self.inner.write_all(b"\r\n")?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
-impl ToString for Response {
-    fn to_string(&self) -> String {
-        format!(
-            r#"{} {} {}
-Server: {}
-Date: {}
-Content-Type: {}
-Content-Length: {}
-Connection: keep-alive
-
-{}
-"#,
-            self.http_version.to_string(),
-            self.status_code,
-            STATUS_CODE_MAPPING.get(&self.status_code).unwrap(),
-            self.server_name,
-            self.date.to_rfc2822(),
-            self.content_type,
-            self.content_length,
-            self.content,
-        )
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempPath;
+
+    fn written(resp: Response) -> String {
+        let mut out = Vec::new();
+        resp.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_framing() {
+        let mut resp = Response::new(HTTPVersion::HTTP11, StatusCode::OK)
+            .with_content_type("text/plain")
+            .with_header("X-Thing", "a")
+            .with_header("Content-Length", "99")
+            .with_body("hi");
+        resp.date = DateTime::from_utc(chrono::NaiveDateTime::from_timestamp(0, 0), Utc);
+        assert_eq!(
+            written(resp),
+            "HTTP/1.1 200 OK\r\nDate: Thu, 01 Jan 1970 00:00:00 GMT\r\n\
+             Content-Type: text/plain\r\nX-Thing: a\r\nContent-Length: 2\r\n\
+             Connection: keep-alive\r\n\r\nhi"
+        );
+
+        let resp =
+            Response::new(HTTPVersion::HTTP11, StatusCode::OK).with_stream(&b"streamed"[..], None);
+        let out = written(resp);
+        assert!(out.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(out.ends_with("\r\n\r\n8\r\nstreamed\r\n0\r\n\r\n"));
+
+        // HTTP/1.0 has no chunks, the end of the body is the end of the connection
+        let resp =
+            Response::new(HTTPVersion::HTTP10, StatusCode::OK).with_stream(&b"streamed"[..], None);
+        assert!(!resp.keeps_alive());
+        let out = written(resp);
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\nstreamed"));
+    }
+
+    #[test]
+    fn test_binary_body() {
+        let mut resp =
+            Response::new(HTTPVersion::HTTP11, StatusCode::OK).with_body(vec![0, 0xff, b'\n']);
+        resp.strip_body();
+        let mut out = Vec::new();
+        resp.write_to(&mut out).unwrap();
+        assert!(out.ends_with(b"Content-Length: 3\r\nConnection: keep-alive\r\n\r\n"));
+
+        let resp =
+            Response::new(HTTPVersion::HTTP11, StatusCode::OK).with_body(vec![0, 0xff, b'\n']);
+        let mut out = Vec::new();
+        resp.write_to(&mut out).unwrap();
+        assert!(out.ends_with(b"\r\n\r\n\x00\xff\n"));
+    }
+
+    #[test]
+    fn test_file_body() {
+        use std::io::{Seek, SeekFrom};
+        use std::net::TcpListener;
+
+        let path = TempPath::new("body");
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        let open = |offset| {
+            let mut file = fs::File::open(&path).unwrap();
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file
+        };
+
+        let mut out = Vec::new();
+        let resp = Response::new(HTTPVersion::HTTP11, StatusCode::OK).with_file(open(10), 1000);
+        resp.write_to(&mut out).unwrap();
+        assert!(out.ends_with(&content[10..1010]));
+
+        // plain TCP goes through sendfile where there is one
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let file = open(0);
+        let writer = std::thread::spawn(move || {
+            let resp = Response::new(HTTPVersion::HTTP10, StatusCode::OK)
+                .with_file(file, content.len() as u64);
+            resp.write_to(&mut server).unwrap();
+            content
+        });
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        let content = writer.join().unwrap();
+        assert!(received.ends_with(&content));
+
+        // a file shorter than announced can't complete the response
+        let mut out = Vec::new();
+        let empty = fs::File::open("/dev/null").unwrap();
+        let resp = Response::new(HTTPVersion::HTTP11, StatusCode::OK).with_file(empty, 10);
+        let err = resp.write_to(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_status_line() {
+        let status = StatusCode::from_u16(599).unwrap();
+        let out = written(Response::new(HTTPVersion::HTTP11, status));
+        assert!(out.starts_with("HTTP/1.1 599 \r\n"));
+
+        let resp = Response::new(HTTPVersion::HTTP11, StatusCode::OK).with_reason("Fine\r\nX: y");
+        assert!(written(resp).starts_with("HTTP/1.1 200 FineX: y\r\n"));
+
+        let resp = Response::new(HTTPVersion::HTTP11, StatusCode::NO_CONTENT).with_body("x");
+        let out = written(resp);
+        assert!(!out.contains("Content-Length") && out.ends_with("\r\n\r\n"));
     }
 }
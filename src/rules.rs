@@ -0,0 +1,213 @@
+use crate::config;
+use crate::http::{fmt_http_date, StatusCode};
+use crate::parser::is_tchar;
+use crate::response::Response;
+use chrono::{Duration, Utc};
+
+/// Headers set on responses to the paths a rule matches
+#[derive(Debug, Clone, Default)]
+pub struct Rule {
+    /// glob over the request path, `*` stays within a segment, `**` does not
+    path: Option<String>,
+    /// file extensions, without their dot
+    extensions: Vec<String>,
+    cache_control: Option<String>,
+    /// seconds from the time of the response
+    expires: Option<u64>,
+    headers: Vec<(String, String)>,
+}
+
+impl Rule {
+    /// Builds a rule from the config
+    /// header fields that can't be sent are left out with a warning
+    pub fn from_config(cfg: &config::Rule) -> Self {
+        let mut headers = Vec::new();
+        for (name, value) in cfg.headers.iter().flatten() {
+            if is_field(name, value) {
+                headers.push((name.clone(), value.clone()));
+            } else {
+                eprintln!("Ignoring invalid header in rule: {}", name);
+            }
+        }
+        let cache_control = cfg.cache_control.clone().filter(|value| {
+            let valid = is_field("Cache-Control", value);
+            if !valid {
+                eprintln!("Ignoring invalid Cache-Control in rule: {}", value);
+            }
+            valid
+        });
+        Self {
+            path: cfg
+                .path
+                .as_ref()
+                .map(|p| p.trim_start_matches('/').to_string()),
+            extensions: cfg
+                .extensions
+                .iter()
+                .flatten()
+                .map(|e| e.trim_start_matches('.').to_ascii_lowercase())
+                .collect(),
+            cache_control,
+            expires: cfg.expires,
+            headers,
+        }
+    }
+
+    /// Checks if the rule applies to a request path
+    /// a rule with a glob and extensions needs both to match
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_start_matches('/');
+        let glob_matches = match &self.path {
+            Some(glob) => glob_match(glob, path),
+            None => true,
+        };
+        let extension_matches = self.extensions.is_empty()
+            || path
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.rsplit_once('.'))
+                .is_some_and(|(_, ext)| {
+                    self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))
+                });
+        glob_matches && extension_matches
+    }
+
+    /// Sets the headers of the rule, replacing any already set
+    pub fn apply(&self, resp: &mut Response) {
+        if let Some(value) = &self.cache_control {
+            resp.set_header("Cache-Control", value);
+        }
+        if let Some(secs) = self.expires {
+            let secs = i64::try_from(secs).unwrap_or(i64::MAX).min(MAX_EXPIRES);
+            let expires = Utc::now() + Duration::seconds(secs);
+            resp.set_header("Expires", fmt_http_date(&expires));
+        }
+        for (name, value) in &self.headers {
+            resp.set_header(name, value);
+        }
+    }
+}
+
+/// Expires further out than this is capped, to keep the date representable
+const MAX_EXPIRES: i64 = 10 * 365 * 24 * 60 * 60;
+
+/// Rules of a server, applied in the order the config lists them
+#[derive(Debug, Clone, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn from_config(cfg: Option<&Vec<config::Rule>>) -> Self {
+        Self(
+            cfg.iter()
+                .flat_map(|rules| rules.iter())
+                .map(Rule::from_config)
+                .collect(),
+        )
+    }
+
+    /// Sets the headers of every rule matching the path, so a later rule
+    /// overrides the headers an earlier one set
+    ///
+    /// Only successful and 304 responses are touched, a missing file must
+    /// not be cached as long as the file itself would be.
+    pub fn apply(&self, path: &str, resp: &mut Response) {
+        let status = resp.status_code;
+        if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+            return;
+        }
+        for rule in self.0.iter().filter(|rule| rule.matches(path)) {
+            rule.apply(resp);
+        }
+    }
+}
+
+/// Checks that a header field from the config can be sent as is
+pub fn is_field(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(is_tchar)
+        && value.chars().all(|c| c == '\t' || !c.is_control())
+}
+
+/// Matches a path against a glob
+///
+/// `?` matches one character and `*` any number of them within a path
+/// segment, `**` also matches across segments. Matching keeps a set of
+/// positions in the glob so it stays linear in the length of the path.
+pub fn glob_match(glob: &str, path: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    // states[i] is set when glob[..i] matches the path read so far
+    let mut states = vec![false; glob.len() + 1];
+    states[0] = true;
+    close_stars(&glob, &mut states);
+
+    for c in path.chars() {
+        let mut next = vec![false; glob.len() + 1];
+        for i in (0..glob.len()).filter(|&i| states[i]) {
+            match glob[i] {
+                '*' if glob.get(i + 1) == Some(&'*') => next[i] = true,
+                '*' if c != '/' => next[i] = true,
+                '*' => {}
+                '?' if c != '/' => next[i + 1] = true,
+                g if g == c => next[i + 1] = true,
+                _ => {}
+            }
+        }
+        close_stars(&glob, &mut next);
+        if !next.contains(&true) {
+            return false;
+        }
+        states = next;
+    }
+    states[glob.len()]
+}
+
+/// Lets every reached star match nothing, and `**/` match no segment
+fn close_stars(glob: &[char], states: &mut [bool]) {
+    for i in 0..glob.len() {
+        if states[i] && glob[i] == '*' {
+            if glob.get(i + 1) == Some(&'*') {
+                states[i + 2] = true;
+                if glob.get(i + 2) == Some(&'/') {
+                    states[i + 3] = true;
+                }
+            } else {
+                states[i + 1] = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("static/**", "static/app.3f2a.js"));
+        assert!(glob_match("static/**", "static/css/site.css"));
+        assert!(!glob_match("static/**", "index.html"));
+        assert!(glob_match("static/*.js", "static/app.js"));
+        assert!(!glob_match("static/*.js", "static/lib/app.js"));
+        assert!(glob_match("**/*.html", "index.html"));
+        assert!(glob_match("**/*.html", "a/b/index.html"));
+        assert!(glob_match("img/??.png", "img/ab.png"));
+        assert!(!glob_match("img/??.png", "img/abc.png"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("a", ""));
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let cfg = config::Rule {
+            path: Some("/static/**".to_string()),
+            extensions: Some(vec![".JS".to_string(), "css".to_string()]),
+            ..Default::default()
+        };
+        let rule = Rule::from_config(&cfg);
+        assert!(rule.matches("/static/app.js"));
+        assert!(rule.matches("static/css/site.css"));
+        assert!(!rule.matches("/static/logo.png"));
+        assert!(!rule.matches("/app.js"));
+        assert!(!rule.matches("/static/js"));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config;
+use crate::response::Response;
+use crate::rules::{glob_match, is_field};
+
+/// Seconds browsers remember to only use HTTPS, one year
+const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+
+/// Policy of the strict preset, everything from the server's own origin
+const STRICT_CSP: &str =
+    "default-src 'self'; object-src 'none'; base-uri 'self'; frame-ancestors 'none'";
+
+/// Sets of security headers to start from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// no headers but HSTS
+    None,
+    /// headers that don't change how pages behave
+    Basic,
+    /// adds a same origin content security policy and denies framing
+    Strict,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Some(Preset::None),
+            "basic" => Some(Preset::Basic),
+            "strict" => Some(Preset::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// Security headers sent with every response of a server
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    /// Strict-Transport-Security, only set for TLS servers
+    hsts: Option<String>,
+    content_security_policy: Option<String>,
+    /// policies for matching paths, the first match wins
+    csp_overrides: Vec<(String, Option<String>)>,
+    /// headers that don't depend on the path
+    headers: Vec<(&'static str, String)>,
+}
+
+impl SecurityHeaders {
+    /// Builds the headers from the preset, then the fields the config sets
+    /// HSTS is on for TLS servers unless its max age is set to 0
+    pub fn from_config(cfg: Option<&config::SecurityHeaders>, tls: bool) -> Self {
+        let default = config::SecurityHeaders::default();
+        let cfg = cfg.unwrap_or(&default);
+        let preset = match cfg.preset.as_deref() {
+            Some(name) => Preset::from_name(name).unwrap_or_else(|| {
+                eprintln!("Unknown security headers preset {}, using basic", name);
+                Preset::Basic
+            }),
+            None => Preset::Basic,
+        };
+        let strict = preset == Preset::Strict;
+        let preset_value = |basic: &str, strict_value: &str| match preset {
+            Preset::None => None,
+            Preset::Basic => Some(basic.to_string()),
+            Preset::Strict => Some(strict_value.to_string()),
+        };
+        // an empty value in the config turns a header of the preset off
+        let pick = |name: &str, value: &Option<String>, preset: Option<String>| {
+            let value = value.clone().or(preset).filter(|v| !v.is_empty())?;
+            if !is_field(name, &value) {
+                eprintln!("Ignoring invalid {} in security headers: {}", name, value);
+                return None;
+            }
+            Some(value)
+        };
+
+        let hsts_max_age = cfg.hsts_max_age.unwrap_or(DEFAULT_HSTS_MAX_AGE);
+        let hsts = (tls && hsts_max_age > 0).then(|| {
+            let mut value = format!("max-age={}", hsts_max_age);
+            if cfg.hsts_include_subdomains.unwrap_or(strict) {
+                value.push_str("; includeSubDomains");
+            }
+            if cfg.hsts_preload.unwrap_or(false) {
+                value.push_str("; preload");
+            }
+            value
+        });
+
+        let mut headers = Vec::new();
+        let fields = [
+            (
+                "X-Content-Type-Options",
+                &cfg.content_type_options,
+                preset_value("nosniff", "nosniff"),
+            ),
+            (
+                "X-Frame-Options",
+                &cfg.frame_options,
+                preset_value("SAMEORIGIN", "DENY"),
+            ),
+            (
+                "Referrer-Policy",
+                &cfg.referrer_policy,
+                preset_value("strict-origin-when-cross-origin", "no-referrer"),
+            ),
+            (
+                "Cross-Origin-Opener-Policy",
+                &cfg.cross_origin_opener_policy,
+                strict.then(|| "same-origin".to_string()),
+            ),
+            ("Permissions-Policy", &cfg.permissions_policy, None),
+        ];
+        for (name, value, preset) in fields {
+            headers.extend(pick(name, value, preset).map(|value| (name, value)));
+        }
+
+        Self {
+            hsts,
+            content_security_policy: pick(
+                "Content-Security-Policy",
+                &cfg.content_security_policy,
+                strict.then(|| STRICT_CSP.to_string()),
+            ),
+            csp_overrides: cfg
+                .csp_overrides
+                .iter()
+                .flatten()
+                .map(|o| {
+                    let policy = pick("Content-Security-Policy", &Some(o.policy.clone()), None);
+                    (o.path.trim_start_matches('/').to_string(), policy)
+                })
+                .collect(),
+            headers,
+        }
+    }
+
+    /// Content security policy for a request path
+    pub fn policy_for(&self, path: &str) -> Option<&str> {
+        let path = path.trim_start_matches('/');
+        match self
+            .csp_overrides
+            .iter()
+            .find(|(glob, _)| glob_match(glob, path))
+        {
+            Some((_, policy)) => policy.as_deref(),
+            None => self.content_security_policy.as_deref(),
+        }
+    }
+
+    /// Sets the headers on a response to the path
+    pub fn apply(&self, path: &str, resp: &mut Response) {
+        if let Some(hsts) = &self.hsts {
+            resp.set_header("Strict-Transport-Security", hsts);
+        }
+        if let Some(policy) = self.policy_for(path) {
+            resp.set_header("Content-Security-Policy", policy);
+        }
+        for (name, value) in &self.headers {
+            resp.set_header(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{HTTPVersion, StatusCode};
+
+    fn headers(cfg: &config::SecurityHeaders, tls: bool, path: &str) -> Response {
+        let mut resp = Response::new(HTTPVersion::HTTP11, StatusCode::OK);
+        SecurityHeaders::from_config(Some(cfg), tls).apply(path, &mut resp);
+        resp
+    }
+
+    #[test]
+    fn test_presets() {
+        let basic = headers(&config::SecurityHeaders::default(), false, "/");
+        assert_eq!(basic.get_header("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(basic.get_header("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(basic.get_header("Strict-Transport-Security"), None);
+        assert_eq!(basic.get_header("Content-Security-Policy"), None);
+
+        let cfg = config::SecurityHeaders {
+            preset: Some("strict".to_string()),
+            frame_options: Some(String::new()),
+            ..Default::default()
+        };
+        let strict = headers(&cfg, true, "/");
+        assert_eq!(
+            strict.get_header("Strict-Transport-Security"),
+            Some("max-age=31536000; includeSubDomains")
+        );
+        assert_eq!(
+            strict.get_header("Content-Security-Policy"),
+            Some(STRICT_CSP)
+        );
+        assert_eq!(strict.get_header("X-Frame-Options"), None);
+
+        let cfg = config::SecurityHeaders {
+            preset: Some("none".to_string()),
+            ..Default::default()
+        };
+        let none = headers(&cfg, true, "/");
+        assert!(none.get_header("Strict-Transport-Security").is_some());
+        assert_eq!(none.get_header("X-Content-Type-Options"), None);
+    }
+
+    #[test]
+    fn test_csp_overrides() {
+        let cfg = config::SecurityHeaders {
+            content_security_policy: Some("default-src 'self'".to_string()),
+            csp_overrides: Some(vec![
+                config::CspOverride {
+                    path: "/embed/**".to_string(),
+                    policy: "default-src *".to_string(),
+                },
+                config::CspOverride {
+                    path: "/legacy/**".to_string(),
+                    policy: String::new(),
+                },
+            ]),
+            ..Default::default()
+        };
+        let security = SecurityHeaders::from_config(Some(&cfg), false);
+        assert_eq!(
+            security.policy_for("/index.html"),
+            Some("default-src 'self'")
+        );
+        assert_eq!(
+            security.policy_for("/embed/a/b.html"),
+            Some("default-src *")
+        );
+        assert_eq!(security.policy_for("/legacy/old.html"), None);
+    }
+}
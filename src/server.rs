@@ -1,90 +1,184 @@
-use crate::config::Tls;
-use crate::file_manager::FileManager;
+use crate::conditional::{self, Precondition, Validators};
+use crate::config::Config;
+use crate::cors::Cors;
+use crate::encoding::{self, Compression};
+use crate::error::{Error, Result};
+use crate::file_manager::{FileManager, Page, SymlinkPolicy};
+use crate::h2::{self, Upgrade};
+use crate::http::{HTTPVersion, Method, StatusCode};
 use crate::log::{log_request, log_response};
-use crate::request::Request;
-use crate::response::Response;
+use crate::range::{self, MultipartRanges, Ranges};
+use crate::request::{Limits, Request, RequestReader};
+use crate::response::{Response, Sink};
+use crate::rules::Rules;
+use crate::security::SecurityHeaders;
+use chrono::Utc;
 use rustls::{Certificate, PrivateKey};
-use rustls::{ServerConfig, ServerConnection, Stream as TlsStream};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use rustls_pemfile::Item::{PKCS8Key, RSAKey};
 use rustls_pemfile::{certs, read_one};
 use std::fs;
 use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::io::{self, ErrorKind};
+use std::io::{Seek, SeekFrom};
 use std::net::TcpListener;
 use std::net::TcpStream;
-use std::str;
 use std::sync::Arc;
+use std::time::Duration;
 use threadpool::ThreadPool;
 
 /// Web server name
 static NAME: &str = "Fimafeng";
 
+/// ALPN protocol ids offered during the TLS handshake, in order of preference
+const ALPN_H2: &[u8] = b"h2";
+const ALPN_HTTP11: &[u8] = b"http/1.1";
+
+/// Methods the server has a handler for, answered when the config does
+/// not list any, the config can only narrow them
+static METHODS: [&str; 3] = ["GET", "HEAD", "OPTIONS"];
+
+/// Seconds an idle connection is kept open by default
+const DEFAULT_KEEP_ALIVE_TIMEOUT: u64 = 5;
+
+/// Requests served on one connection by default
+const DEFAULT_MAX_REQUESTS: usize = 100;
+
 /// Server object
 pub struct Server {
     port: u16,
     host: String,
-    file_manager: FileManager,
     // number of threads
     threads: usize,
     server_config: Option<ServerConfig>,
-    has_tls: bool,
+    context: Arc<Context>,
+}
+
+/// Per server settings shared with the connection workers
+pub struct Context {
+    file_manager: FileManager,
+    // methods the server answers
+    methods: Vec<Method>,
+    // how long to wait for the next request on an idle connection
+    keep_alive_timeout: Duration,
+    // requests served before a connection is closed
+    max_requests: usize,
+    // sizes a request may not exceed
+    limits: Limits,
+    // entity tags of files are sent as weak validators
+    weak_etags: bool,
+    // which responses are compressed
+    compression: Compression,
+    // headers set on responses to matching paths
+    rules: Rules,
+    // security headers sent with every response
+    security_headers: SecurityHeaders,
+    // cross-origin access to responses
+    cors: Cors,
 }
 
 impl Server {
     /// Returns a new Server with a properly initialized file manager
-    pub fn new(host: &str, web_dir: &str, port: u16, threads: usize, opt_tls: Option<Tls>) -> Self {
-        let mut has_tls = false;
-        let mut server_config = None;
-        if let Some(tls) = opt_tls {
-            server_config = Server::make_config(tls.cert.as_str(), tls.key.as_str());
-            has_tls = server_config.is_some();
-        }
-
-        Self {
-            host: host.to_string(),
-            port,
-            file_manager: FileManager::new(web_dir),
-            threads,
-            has_tls,
+    /// fails if the TLS certificate or key can't be loaded
+    pub fn new(cfg: Config) -> Result<Self> {
+        let server_config = match &cfg.tls {
+            Some(tls) => Some(Server::make_config(&tls.cert, &tls.key)?),
+            None => None,
+        };
+        let has_tls = server_config.is_some();
+
+        let methods = match cfg.methods {
+            Some(methods) => methods
+                .iter()
+                .filter(|m| {
+                    let handled = METHODS.contains(&m.as_str());
+                    if !handled {
+                        eprintln!("Ignoring method {}, only files are served", m);
+                    }
+                    handled
+                })
+                .map(|m| Method::from(m.as_str()))
+                .collect(),
+            None => METHODS.iter().map(|m| Method::from(*m)).collect(),
+        };
+
+        let symlinks = match cfg.symlinks.as_deref() {
+            Some(name) => SymlinkPolicy::from_name(name).unwrap_or_else(|| {
+                eprintln!("Unknown symlink policy {}, using within_root", name);
+                SymlinkPolicy::WithinRoot
+            }),
+            None => SymlinkPolicy::WithinRoot,
+        };
+
+        let defaults = Limits::default();
+        let context = Context {
+            file_manager: FileManager::new(cfg.directory.as_str(), symlinks),
+            methods,
+            keep_alive_timeout: Duration::from_secs(
+                cfg.keep_alive_timeout.unwrap_or(DEFAULT_KEEP_ALIVE_TIMEOUT),
+            ),
+            max_requests: cfg.max_requests.unwrap_or(DEFAULT_MAX_REQUESTS).max(1),
+            limits: Limits {
+                request_line: cfg.max_request_line.unwrap_or(defaults.request_line),
+                header_bytes: cfg.max_header_bytes.unwrap_or(defaults.header_bytes),
+                headers: cfg.max_headers.unwrap_or(defaults.headers),
+                body: cfg.max_body_size.unwrap_or(defaults.body),
+            },
+            weak_etags: cfg.weak_etags.unwrap_or(false),
+            compression: Compression::from_config(cfg.compression.as_ref()),
+            rules: Rules::from_config(cfg.rules.as_ref()),
+            cors: Cors::from_config(cfg.cors.as_ref()),
+            security_headers: SecurityHeaders::from_config(cfg.security_headers.as_ref(), has_tls),
+        };
+
+        Ok(Self {
+            host: cfg.host,
+            port: cfg.port,
+            threads: cfg.thread_count,
             server_config,
-        }
+            context: Arc::new(context),
+        })
     }
 
     /// Builds a  serverconfig
     /// should be invoked once
-    fn make_config(cert: &str, key: &str) -> Option<ServerConfig> {
-        ServerConfig::builder()
+    fn make_config(cert: &str, key: &str) -> Result<ServerConfig> {
+        let mut config = ServerConfig::builder()
             .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(
-                Server::load_certs(cert).unwrap(),
-                Server::load_private_key(key).unwrap(),
-            )
-            .ok()
+            .with_single_cert(Server::load_certs(cert)?, Server::load_private_key(key)?)
+            .map_err(|e| Error::Config(format!("{} and {}: {}", cert, key, e)))?;
+        config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()];
+        Ok(config)
     }
 
     /// Reads certificate
-    fn load_certs(filename: &str) -> Option<Vec<Certificate>> {
-        let cert_raw = fs::read_to_string(filename).unwrap();
+    fn load_certs(filename: &str) -> Result<Vec<Certificate>> {
+        let invalid = |reason: String| Error::Config(format!("{}: {}", filename, reason));
+        let cert_raw = fs::read_to_string(filename).map_err(|e| invalid(e.to_string()))?;
         let mut reader = BufReader::new(cert_raw.as_bytes());
-        let cert = certs(&mut reader)
-            .ok()?
-            .iter()
-            .map(|v| Certificate(v.clone()))
+        let certs: Vec<Certificate> = certs(&mut reader)
+            .map_err(|e| invalid(e.to_string()))?
+            .into_iter()
+            .map(Certificate)
             .collect();
-
-        Some(cert)
+        if certs.is_empty() {
+            return Err(invalid("no certificates".to_string()));
+        }
+        Ok(certs)
     }
 
     /// Reads private key
-    fn load_private_key(filename: &str) -> Option<PrivateKey> {
-        let key_raw = fs::read_to_string(filename).unwrap();
+    fn load_private_key(filename: &str) -> Result<PrivateKey> {
+        let invalid = |reason: String| Error::Config(format!("{}: {}", filename, reason));
+        let key_raw = fs::read_to_string(filename).map_err(|e| invalid(e.to_string()))?;
         let mut reader = BufReader::new(key_raw.as_bytes());
-        match read_one(&mut reader).ok()? {
-            Some(RSAKey(key)) => Some(PrivateKey(key)),
-            Some(PKCS8Key(key)) => Some(PrivateKey(key)),
-            _ => None,
+        match read_one(&mut reader).map_err(|e| invalid(e.to_string()))? {
+            Some(RSAKey(key)) => Ok(PrivateKey(key)),
+            Some(PKCS8Key(key)) => Ok(PrivateKey(key)),
+            _ => Err(invalid("no RSA or PKCS#8 private key".to_string())),
         }
     }
 
@@ -107,15 +201,20 @@ impl Server {
         let pool = ThreadPool::new(self.threads);
 
         for stream in listener.incoming() {
-            let stream = stream.unwrap();
+            // running out of descriptors or a client giving up mid-handshake
+            // only fails this connection, the server keeps accepting
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
 
-            let mut tls_config = None;
-            if self.has_tls {
-                tls_config = Some(Arc::new(self.server_config.clone().unwrap()));
-            }
+            let tls_config = self.server_config.clone().map(Arc::new);
 
-            let fm = self.file_manager.clone();
-            pool.execute(move || handle_connection(&fm, stream, tls_config));
+            let ctx = self.context.clone();
+            pool.execute(move || handle_connection(&ctx, stream, tls_config));
         }
 
         println!("Server shutting down");
@@ -123,89 +222,537 @@ impl Server {
 }
 
 pub fn handle_connection(
-    fm: &FileManager,
+    ctx: &Context,
     mut stream: TcpStream,
     // The option helps the function determine if tls is enabled
     tls_config: Option<Arc<ServerConfig>>,
 ) {
-    let mut buffer = [0; 512];
-
-    let req: Request;
+    if let Err(e) = stream.set_read_timeout(Some(ctx.keep_alive_timeout)) {
+        eprintln!("Failed to set read timeout: {}", e);
+        return;
+    }
 
     if let Some(tls_cfg) = tls_config {
         // create tls session
-        let mut session = ServerConnection::new(tls_cfg).unwrap();
-        let mut stream = TlsStream::new(&mut session, &mut stream);
-        stream.read(&mut buffer).unwrap();
-        let http_req_str = str::from_utf8(&buffer).unwrap();
-        req = Request::try_from(http_req_str).unwrap();
+        let mut session = match ServerConnection::new(tls_cfg) {
+            Ok(session) => session,
+            Err(e) => {
+                eprintln!("Failed to start TLS session: {}", e);
+                return;
+            }
+        };
+        // the handshake settles which protocol to speak
+        while session.is_handshaking() {
+            if let Err(e) = session.complete_io(&mut stream) {
+                if !is_timeout_io(&e) {
+                    eprintln!("TLS handshake failed: {}", e);
+                }
+                return;
+            }
+        }
+        let h2 = session.alpn_protocol() == Some(ALPN_H2);
+        let stream = StreamOwned::new(session, stream);
+        if h2 {
+            serve_h2(ctx, stream, Vec::new(), None);
+        } else {
+            serve_stream(ctx, stream, true);
+        }
     } else {
-        stream.read(&mut buffer).unwrap();
-        let http_req_str = str::from_utf8(&buffer).unwrap();
-        req = Request::try_from(http_req_str).unwrap();
-    }
-
-    log_request(&req);
-    let resp: Response;
-
-    if req.target() == "" {
-        let file = fm.template_dir(fm.base_path().as_str()).unwrap();
-        resp = Response::new(
-            req.http_ver(),
-            200,
-            file.content,
-            file.content_type,
-            file.content_length,
-            NAME.to_string(),
-        );
+        serve_stream(ctx, stream, false);
+    }
+}
+
+/// Serves requests off the stream in order until the connection is closed
+/// cleartext connections may switch to HTTP/2
+fn serve_stream<S: Read + Sink>(ctx: &Context, stream: S, tls: bool) {
+    let mut reader = RequestReader::new(stream).with_limits(ctx.limits);
+    if !tls {
+        match reader.starts_with(h2::PREFACE) {
+            Ok(true) => {
+                let (stream, buffered) = reader.into_parts();
+                return serve_h2(ctx, stream, buffered, None);
+            }
+            Ok(false) => {}
+            Err(Error::Io(e)) => {
+                eprintln!("Failed to read request: {}", e);
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+
+    for served in 1..=ctx.max_requests {
+        let mut req = match reader.read_request_head() {
+            Ok(Some(req)) => req,
+            Ok(None) => return,
+            Err(e) => return refuse_read(&mut reader, e),
+        };
+        // a client waiting to send its body is spared it when the request is
+        // refused anyway, and the connection closes as the body is unread
+        let refused = reader.awaits_continue() && refuses(ctx, &req);
+        if !refused {
+            if let Err(e) = reader.read_request_body(&mut req) {
+                return refuse_read(&mut reader, e);
+            }
+        }
+
+        if !tls && !refused {
+            if let Some(settings) = h2::upgrade_settings(&req) {
+                let stream = reader.get_mut();
+                let switching = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+                if let Err(e) = stream.write_all(switching).and_then(|_| stream.flush()) {
+                    eprintln!("Failed to write response: {}", e);
+                    return;
+                }
+                let (stream, buffered) = reader.into_parts();
+                let upgrade = Upgrade {
+                    request: req,
+                    settings,
+                };
+                return serve_h2(ctx, stream, buffered, Some(upgrade));
+            }
+        }
+
+        log_request(&req);
+        let mut resp = respond(ctx, &req);
+        resp.keep_alive = !refused && req.keep_alive() && served < ctx.max_requests;
+        let keep_alive = resp.keeps_alive();
+        if keep_alive {
+            resp.add_header(
+                "Keep-Alive",
+                format!(
+                    "timeout={}, max={}",
+                    ctx.keep_alive_timeout.as_secs(),
+                    ctx.max_requests - served
+                ),
+            );
+        }
         log_response(&resp);
-        stream.write_all(resp.to_string().as_bytes()).unwrap();
-        stream.flush().unwrap();
+
+        let stream = reader.get_mut();
+        if let Err(e) = resp.write_to(stream).and_then(|_| stream.flush()) {
+            eprintln!("Failed to write response: {}", e);
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Answers a request that could not be read and closes the connection,
+/// the stream can't be trusted past a bad request
+fn refuse_read<S: Read + Sink>(reader: &mut RequestReader<S>, e: Error) {
+    if let Error::Io(e) = e {
+        eprintln!("Failed to read request: {}", e);
         return;
     }
-    // check target
-    let target = req.target();
-    if FileManager::is_dir(target.as_str()) {
-        let file = fm.template_dir(target.as_str()).unwrap();
-        resp = Response::new(
-            req.http_ver(),
-            200,
-            file.content,
-            file.content_type,
-            file.content_length,
-            NAME.to_string(),
-        );
+    let mut resp = status_response(HTTPVersion::HTTP11, e.status_code());
+    resp.keep_alive = false;
+    log_response(&resp);
+    let stream = reader.get_mut();
+    if let Err(e) = resp.write_to(stream).and_then(|_| stream.flush()) {
+        eprintln!("Failed to write response: {}", e);
+    }
+}
+
+/// Checks if a request is refused whatever its body holds
+fn refuses(ctx: &Context, req: &Request) -> bool {
+    let method = req.method();
+    if ctx.cors.is_preflight(req) {
+        return false;
+    }
+    if !ctx.methods.contains(&method) {
+        return true;
+    }
+    method != Method::Options && ctx.file_manager.resolve(&req.target()).is_err()
+}
+
+/// Serves an HTTP/2 connection with the same handling as HTTP/1 requests
+fn serve_h2<S: Read + Write>(
+    ctx: &Context,
+    stream: S,
+    buffered: Vec<u8>,
+    upgrade: Option<Upgrade>,
+) {
+    let handler = |req: &Request| {
+        log_request(req);
+        let resp = respond(ctx, req);
         log_response(&resp);
-        stream.write_all(resp.to_string().as_bytes()).unwrap();
-        stream.flush().unwrap();
-        return;
-    } else if fm.file_exist(target.as_str()) {
-        let file = fm.get_file(target.as_str()).unwrap();
-        resp = Response::new(
-            req.http_ver(),
-            200,
-            file.content,
-            file.content_type,
-            file.content_length,
-            NAME.to_string(),
+        resp
+    };
+    h2::serve(stream, buffered, upgrade, &ctx.limits, &handler);
+}
+
+/// Checks if reading failed because the connection sat idle too long
+fn is_timeout_io(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Builds the response for a request according to its method
+fn respond(ctx: &Context, req: &Request) -> Response {
+    let method = req.method();
+    let mut resp = if ctx.cors.is_preflight(req) {
+        // preflights are answered whether or not OPTIONS is allowed
+        let mut resp = new_response(req.http_ver(), StatusCode::NO_CONTENT);
+        for (name, value) in ctx.cors.preflight_headers(req) {
+            resp.add_header(name, value);
+        }
+        resp
+    } else {
+        let allowed = ctx.methods.contains(&method);
+        match method {
+            Method::Get | Method::Head if allowed => {
+                serve_target(ctx, req).unwrap_or_else(|e| error_response(&ctx.file_manager, req, e))
+            }
+            Method::Options if allowed => options(req, &ctx.methods),
+            // there is nothing to tunnel to, only files are served
+            Method::Connect => status_response(req.http_ver(), StatusCode::NOT_IMPLEMENTED),
+            _ => not_allowed(req, &ctx.methods),
+        }
+    };
+    if !ctx.cors.is_preflight(req) {
+        for (name, value) in ctx.cors.headers(req) {
+            resp.add_header(name, value);
+        }
+    }
+    ctx.security_headers.apply(&req.uri().path, &mut resp);
+    ctx.rules.apply(&req.uri().path, &mut resp);
+    if method == Method::Head {
+        resp.strip_body();
+    }
+    resp
+}
+
+/// Answers a request that failed with the status its error maps to
+fn error_response(fm: &FileManager, req: &Request, e: Error) -> Response {
+    if let Error::NotFound(_) = e {
+        if let Ok(file) = fm.not_found() {
+            return new_response(req.http_ver(), StatusCode::NOT_FOUND)
+                .with_content_type(&file.content_type)
+                .with_file(file.handle, file.content_length);
+        }
+    }
+    if let Error::Internal(_) | Error::Io(_) = e {
+        eprintln!("Failed to serve {}: {}", req.target(), e);
+    }
+    status_response(req.http_ver(), e.status_code())
+}
+
+/// Comma separated list of methods for the Allow header
+fn allow_header(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(|m| m.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Answers an OPTIONS request with the methods the server supports
+fn options(req: &Request, methods: &[Method]) -> Response {
+    new_response(req.http_ver(), StatusCode::OK)
+        .with_content_type("text/plain")
+        .with_header("Allow", allow_header(methods))
+}
+
+/// 405 for known methods the server does not allow, 501 for unknown ones
+fn not_allowed(req: &Request, methods: &[Method]) -> Response {
+    let status_code = if req.method().is_standard() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        StatusCode::NOT_IMPLEMENTED
+    };
+    let mut resp = status_response(req.http_ver(), status_code);
+    resp.add_header("Allow", allow_header(methods));
+    resp
+}
+
+/// Plain text response carrying the status reason as its body
+fn status_response(http_version: HTTPVersion, status_code: StatusCode) -> Response {
+    let content = status_code.reason();
+    new_response(http_version, status_code)
+        .with_content_type("text/plain")
+        .with_body(content)
+}
+
+/// Empty response naming the server
+fn new_response(http_version: HTTPVersion, status_code: StatusCode) -> Response {
+    Response::new(http_version, status_code).with_header("Server", NAME)
+}
+
+/// Serves the file or directory listing the request targets
+fn serve_target(ctx: &Context, req: &Request) -> Result<Response> {
+    let fm = &ctx.file_manager;
+    // an empty target is the served directory itself
+    let target = req.target();
+    if fm.is_dir(target.as_str()) {
+        let page = fm.template_dir(target.as_str())?;
+        return Ok(listing(req, page));
+    }
+
+    serve_file(ctx, req, target.as_str())
+}
+
+/// Sends a file, or the byte ranges of it the request asks for
+///
+/// A precompressed copy of the file is sent when the client accepts its
+/// coding. Otherwise compressible files are compressed as they are sent,
+/// which leaves their length unknown and so never serves ranges.
+fn serve_file(ctx: &Context, req: &Request, target: &str) -> Result<Response> {
+    let fm = &ctx.file_manager;
+    let compression = &ctx.compression;
+    let accept = req.get_header("Accept-Encoding");
+    let file = fm.get_file(target)?;
+
+    let precompressed = fm.precompressed(target, compression.offered());
+    let compressible = compression.compresses(&file.content_type, file.content_length);
+    let (mut file, encoding, on_the_fly) = match encoding::negotiate(accept, &precompressed) {
+        Some(encoding) => (fm.get_encoded(target, encoding)?, Some(encoding), false),
+        None => match encoding::negotiate(accept, &compression.encodings) {
+            Some(encoding) if compressible => (file, Some(encoding), true),
+            _ => (file, None, false),
+        },
+    };
+
+    let length = file.content_length;
+    let mut validators = Validators::for_file(length, file.modified, ctx.weak_etags);
+    if let Some(encoding) = encoding {
+        // every coding is a representation of its own, compressing on the
+        // fly is only promised to give equivalent content
+        validators = validators.with_encoding(encoding.token(), on_the_fly);
+    }
+    let vary = !precompressed.is_empty() || compressible;
+    let with_validators = |mut resp: Response| {
+        for (name, value) in validators.headers() {
+            resp.add_header(name, value);
+        }
+        if vary {
+            resp.add_header("Vary", "Accept-Encoding");
+        }
+        resp
+    };
+
+    match conditional::evaluate(req, &validators) {
+        Precondition::Proceed => {}
+        Precondition::NotModified => {
+            return Ok(with_validators(new_response(
+                req.http_ver(),
+                StatusCode::NOT_MODIFIED,
+            )))
+        }
+        Precondition::Failed => {
+            return Ok(status_response(
+                req.http_ver(),
+                StatusCode::PRECONDITION_FAILED,
+            ))
+        }
+    }
+
+    let respond = |status| {
+        let resp = with_validators(new_response(req.http_ver(), status));
+        match encoding {
+            // a 416 carries no content to have a coding
+            Some(encoding) if status != StatusCode::RANGE_NOT_SATISFIABLE => {
+                resp.with_header("Content-Encoding", encoding.token())
+            }
+            _ => resp,
+        }
+    };
+
+    if on_the_fly {
+        let body = encoding
+            .unwrap()
+            .encode(Box::new(file.handle))
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        return Ok(respond(StatusCode::OK)
+            .with_content_type(&file.content_type)
+            .with_stream(body, None));
+    }
+    let respond = |status| respond(status).with_header("Accept-Ranges", "bytes");
+
+    // Range only applies to GET, and only while If-Range still matches
+    let ranges = match req.get_header("Range") {
+        Some(value) if req.method() == Method::Get => match req.get_header("If-Range") {
+            Some(validator) if !validators.if_range_matches(validator, Utc::now()) => Ranges::Full,
+            _ => range::parse_range(value, length),
+        },
+        _ => Ranges::Full,
+    };
+
+    match ranges {
+        Ranges::Full => Ok(respond(StatusCode::OK)
+            .with_content_type(&file.content_type)
+            .with_file(file.handle, length)),
+        Ranges::Unsatisfiable => {
+            let mut resp = respond(StatusCode::RANGE_NOT_SATISFIABLE);
+            resp.add_header("Content-Range", format!("bytes */{}", length));
+            Ok(resp)
+        }
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            file.handle
+                .seek(SeekFrom::Start(range.start))
+                .map_err(|e| Error::Internal(e.to_string()))?;
+            Ok(respond(StatusCode::PARTIAL_CONTENT)
+                .with_content_type(&file.content_type)
+                .with_header("Content-Range", range.content_range(length))
+                .with_file(file.handle, range.len()))
+        }
+        Ranges::Satisfiable(ranges) => {
+            let body = MultipartRanges::new(file.handle, &ranges, &file.content_type, length);
+            let (content_type, body_length) = (body.content_type(), body.len());
+            Ok(respond(StatusCode::PARTIAL_CONTENT)
+                .with_content_type(&content_type)
+                .with_stream(body, Some(body_length)))
+        }
+    }
+}
+
+/// Directory listings are rendered whole, so they are sent with their length
+fn listing(req: &Request, page: Page) -> Response {
+    new_response(req.http_ver(), StatusCode::OK)
+        .with_content_type(&page.content_type)
+        .with_body(page.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Sends `raw` over a loopback connection served with the settings in
+    /// `yaml` and returns everything written back until the server closes
+    fn exchange(yaml: &str, raw: &str) -> String {
+        let cfg = format!(
+            "thread_count: 1\nhost: 127.0.0.1\nport: 0\ndirectory: resources\n{}",
+            yaml
         );
-        log_response(&resp);
-        stream.write_all(resp.to_string().as_bytes()).unwrap();
-        stream.flush().unwrap();
-        return;
+        let ctx = Server::new(serde_yaml::from_str(&cfg).unwrap())
+            .unwrap()
+            .context;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || handle_connection(&ctx, stream, None));
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client.write_all(raw.as_bytes()).unwrap();
+        let mut out = String::new();
+        client.read_to_string(&mut out).unwrap();
+        server.join().unwrap();
+        out
     }
 
-    // 404
-    let file = fm.not_found().unwrap();
-    resp = Response::new(
-        req.http_ver(),
-        404,
-        file.content,
-        file.content_type,
-        file.content_length,
-        NAME.to_string(),
-    );
-    log_response(&resp);
-    stream.write_all(resp.to_string().as_bytes()).unwrap();
-    stream.flush().unwrap();
+    const GET: &str = "GET /example.yaml HTTP/1.1\r\nHost: a\r\n\r\n";
+
+    #[test]
+    fn test_keep_alive() {
+        let out = exchange("max_requests: 2\n", &GET.repeat(3));
+        let responses: Vec<&str> = out.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].starts_with("200"));
+        assert!(responses[0].contains("Keep-Alive: timeout=5, max=1\r\n"));
+        assert!(responses[0].contains("Connection: keep-alive\r\n"));
+        assert!(responses[1].starts_with("200"));
+        assert!(responses[1].contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_client_close() {
+        let close = "GET /example.yaml HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+        let out = exchange("", &format!("{}{}", close, GET));
+        assert_eq!(out.matches("HTTP/1.1 200").count(), 1);
+        assert!(out.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let start = Instant::now();
+        let out = exchange("keep_alive_timeout: 1\n", GET);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(out.matches("HTTP/1.1 ").count(), 1);
+        assert!(out.starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let upload = |method, target| {
+            format!(
+                "{} {} HTTP/1.1\r\nHost: a\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+                method, target
+            )
+        };
+        let out = exchange("", &upload("GET", "/missing"));
+        assert!(out.starts_with("HTTP/1.1 404 "));
+        assert!(out.contains("Connection: close\r\n"));
+
+        let out = exchange("", &upload("POST", "/example.yaml"));
+        assert!(out.starts_with("HTTP/1.1 405 "));
+
+        // the body is asked for, and never arrives
+        let out = exchange("keep_alive_timeout: 1\n", &upload("GET", "/example.yaml"));
+        assert!(out.starts_with("HTTP/1.1 100 Continue\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_methods() {
+        let send = |yaml, method: &str, target: &str| {
+            let raw = format!(
+                "{} {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+                method, target
+            );
+            exchange(yaml, &raw)
+        };
+
+        let head = send("", "HEAD", "/example.yaml");
+        assert!(head.starts_with("HTTP/1.1 200 "));
+        assert!(!head.contains("Content-Length: 0\r\n"));
+        assert!(head.contains("Content-Length: "));
+        assert!(head.ends_with("\r\n\r\n"));
+
+        let options = send("", "OPTIONS", "*");
+        assert!(options.starts_with("HTTP/1.1 200 "));
+        assert!(options.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+
+        // POST has no handler, a file is never sent back as if for a GET
+        let post = send("", "POST", "/example.yaml");
+        assert!(post.starts_with("HTTP/1.1 405 "));
+        assert!(post.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+
+        // a listed method the server can't handle is dropped from the config
+        let delete = send("methods: [GET, DELETE]\n", "DELETE", "/example.yaml");
+        assert!(delete.starts_with("HTTP/1.1 405 "));
+        assert!(delete.contains("Allow: GET\r\n"));
+
+        let brew = send("", "BREW", "/example.yaml");
+        assert!(brew.starts_with("HTTP/1.1 501 "));
+        assert!(brew.contains("Allow: GET, HEAD, OPTIONS\r\n"));
+    }
+
+    #[test]
+    fn test_http10() {
+        let get = "GET /example.yaml HTTP/1.0\r\n\r\n";
+        let out = exchange("", &get.repeat(2));
+        assert_eq!(out.matches("HTTP/1.0 200").count(), 1);
+        assert!(!out.contains("Keep-Alive"));
+
+        let keep_alive = "GET /example.yaml HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        let out = exchange("", &format!("{}{}", keep_alive, get));
+        assert_eq!(out.matches("HTTP/1.0 200").count(), 2);
+        assert!(out.contains("Connection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let out = exchange(
+            "",
+            "GET / HTTP/3.0\r\nHost: a\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        );
+        assert!(out.starts_with("HTTP/1.1 505 "));
+        assert_eq!(out.matches("HTTP/1.1 ").count(), 1);
+        assert!(out.contains("Connection: close\r\n"));
+    }
 }